@@ -2,7 +2,7 @@
 set -euo pipefail
 
 echo "Installing Morph Bang Dependencies..."
-sudo pacman -S --needed rustup inotify-tools libvips imagemagick pandoc ffmpeg libnotify texlive-bin texlive-xetex poppler ghostscript
+sudo pacman -S --needed rustup libvips imagemagick pandoc ffmpeg libnotify texlive-bin texlive-xetex poppler ghostscript
 
 echo "Setting up Rust toolchain..."
 rustup toolchain install stable --profile minimal
@@ -22,6 +22,9 @@ echo "Installing binary..."
 sudo cp target/release/morph-bang /usr/local/bin/morph-bang
 sudo chmod +x /usr/local/bin/morph-bang
 
+echo "Creating config directory..."
+sudo install -d -m 755 /etc/morph-bang
+
 echo "Creating Systemd service..."
 sudo tee /etc/systemd/system/morph-bang.service <<EOF
 [Unit]
@@ -31,6 +34,7 @@ After=network.target
 [Service]
 User=root
 ExecStart=/usr/local/bin/morph-bang
+ExecReload=/bin/kill -HUP \$MAINPID
 Restart=always
 Nice=-15
 IOSchedulingClass=realtime
@@ -0,0 +1,439 @@
+use crate::chown_path;
+use crate::config::{format_size, Config};
+use crate::store::{
+    blob_path, create_owned_dir, ensure_version_paths_owned, hash_version, is_compressed,
+    is_lineage_id, is_version_name, list_versions, stem_version_dir, store_root, Manifest,
+    VersionRecord, BLOBS_DIR,
+};
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::{Gid, Uid};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the index at the start of an export.
+const INDEX_NAME: &str = "morph-bang-history.json";
+
+/// Layout of the archive; bumped when it changes incompatibly.
+const FORMAT: u32 = 1;
+
+/// Describes an export: every history with its manifest. Next to it the
+/// archive holds `blobs/<hash>[.zst]`, the contents of each version once.
+#[derive(Serialize, Deserialize)]
+struct Index {
+    format: u32,
+    /// Seconds since the Unix epoch.
+    exported_at: u64,
+    histories: Vec<Manifest>,
+}
+
+/// Where a version's contents are kept in an archive. Compressed versions
+/// stay compressed, as in the store.
+fn archived_blob(record: &VersionRecord) -> PathBuf {
+    let compressed = is_compressed(Path::new(&record.file));
+    blob_path(Path::new(BLOBS_DIR), &record.hash, compressed)
+}
+
+/// Implements `morph-bang history export <archive>`: writes the caller's
+/// whole version store to a tar that `import` can read on another machine.
+/// Version directories are keyed by hashes of absolute paths, so the archive
+/// carries each history's manifest, which names the path it belongs to.
+pub fn export(cfg: &Config, dest: &str) -> Result<()> {
+    let root = store_root(cfg, Uid::current().as_raw())?;
+    let mut dirs: Vec<PathBuf> = fs::read_dir(&root)
+        .with_context(|| format!("failed to read {}", root.display()))?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .filter(|e| is_lineage_id(&e.file_name().to_string_lossy()))
+        .map(|e| e.path())
+        .collect();
+    dirs.sort();
+
+    let mut histories = Vec::new();
+    let mut sources = Vec::new();
+    let mut skipped = Vec::new();
+    for dir in dirs {
+        let mut manifest = match Manifest::load(&dir) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                eprintln!("morph-bang: {err:#}");
+                skipped.push(dir);
+                continue;
+            }
+        };
+        if manifest.stem.as_os_str().is_empty() {
+            skipped.push(dir);
+            continue;
+        }
+        // Versions stored before manifests existed are described by what
+        // their files tell.
+        manifest.versions.retain(|r| dir.join(&r.file).is_file());
+        for version in list_versions(&dir) {
+            if manifest.record(&version).is_some() {
+                continue;
+            }
+            manifest.versions.push(VersionRecord {
+                file: version
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                hash: hash_version(&version)?.to_hex().to_string(),
+                size: fs::metadata(&version)?.len(),
+                ..VersionRecord::default()
+            });
+        }
+        manifest.versions.sort_by(|a, b| a.file.cmp(&b.file));
+        sources.push(dir);
+        histories.push(manifest);
+    }
+
+    let index = Index {
+        format: FORMAT,
+        exported_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        histories,
+    };
+    let file = fs::File::create(dest).with_context(|| format!("failed to create {dest}"))?;
+    let mut builder = tar::Builder::new(BufWriter::new(file));
+    let mut body = serde_json::to_vec_pretty(&index)?;
+    body.push(b'\n');
+    let mut header = tar::Header::new_gnu();
+    header.set_size(body.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(index.exported_at);
+    header.set_cksum();
+    builder.append_data(&mut header, INDEX_NAME, body.as_slice())?;
+
+    let mut written = HashSet::new();
+    let (mut versions, mut bytes) = (0, 0);
+    for (manifest, dir) in index.histories.iter().zip(&sources) {
+        for record in &manifest.versions {
+            versions += 1;
+            let name = archived_blob(record);
+            if !written.insert(name.clone()) {
+                continue;
+            }
+            let version = dir.join(&record.file);
+            bytes += fs::metadata(&version)?.len();
+            builder
+                .append_path_with_name(&version, &name)
+                .with_context(|| format!("failed to export {}", version.display()))?;
+        }
+    }
+    builder.into_inner()?.flush()?;
+
+    println!(
+        "Exported {} histories ({versions} versions, {}) to {dest}",
+        index.histories.len(),
+        format_size(bytes)
+    );
+    if !skipped.is_empty() {
+        println!("Skipped {} histories without a manifest:", skipped.len());
+        for dir in &skipped {
+            println!("  {}", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// A `--map OLD=NEW` path prefix rewrite.
+struct PrefixMap {
+    old: PathBuf,
+    new: PathBuf,
+}
+
+impl PrefixMap {
+    fn parse(spec: &str) -> Result<Self> {
+        let (old, new) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("--map expects <old prefix>=<new prefix>, got {spec:?}"))?;
+        let (old, new) = (PathBuf::from(old), PathBuf::from(new));
+        if !old.is_absolute() || !new.is_absolute() {
+            return Err(anyhow!(
+                "--map prefixes must be absolute paths, got {spec:?}"
+            ));
+        }
+        Ok(Self { old, new })
+    }
+}
+
+/// `path` with the longest matching old prefix replaced by its new one.
+fn rewrite(path: &Path, maps: &[PrefixMap]) -> PathBuf {
+    let best = maps
+        .iter()
+        .filter(|m| path.starts_with(&m.old))
+        .max_by_key(|m| m.old.components().count());
+    let Some(map) = best else {
+        return path.to_path_buf();
+    };
+    match path.strip_prefix(&map.old) {
+        Ok(rest) if !rest.as_os_str().is_empty() => map.new.join(rest),
+        _ => map.new.clone(),
+    }
+}
+
+/// Implements `morph-bang history import <archive> [--map OLD=NEW]...`:
+/// adds the histories of an export to the caller's store, keyed by their
+/// paths on this machine. History that already has a directory is merged
+/// with it, and versions already there are left alone.
+pub fn import(cfg: &Config, archive: &str, args: &[String]) -> Result<()> {
+    let mut maps = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--map", Some(spec)) => maps.push(PrefixMap::parse(spec)?),
+            _ => return Err(anyhow!("unexpected argument {arg:?}")),
+        }
+    }
+
+    let (uid, gid) = (Uid::current().as_raw(), Gid::current().as_raw());
+    let root = store_root(cfg, uid)?;
+    // Unpacked inside the store, so blobs move into place without copying.
+    let staging = root.join(format!(".import-{}", std::process::id()));
+    ensure_version_paths_owned(&staging, uid, gid)?;
+    let result = (|| -> Result<()> {
+        let file = fs::File::open(archive).with_context(|| format!("failed to read {archive}"))?;
+        tar::Archive::new(file)
+            .unpack(&staging)
+            .with_context(|| format!("failed to unpack {archive}"))?;
+        let index_path = staging.join(INDEX_NAME);
+        let raw = fs::read(&index_path)
+            .with_context(|| format!("{archive} is not a Morph Bang history export"))?;
+        let index: Index =
+            serde_json::from_slice(&raw).with_context(|| format!("invalid index in {archive}"))?;
+        if index.format != FORMAT {
+            return Err(anyhow!(
+                "{archive} uses export format {}, this Morph Bang reads {FORMAT}",
+                index.format
+            ));
+        }
+        for record in index.histories.iter().flat_map(|h| &h.versions) {
+            check_record(record).with_context(|| format!("invalid index in {archive}"))?;
+        }
+        import_histories(cfg, &root, &staging, index, &maps, uid, gid)
+    })();
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+/// Rejects a record that would place its version outside its directory or
+/// name a blob by anything but a content hash: both come from the archive
+/// and end up in store paths.
+fn check_record(record: &VersionRecord) -> Result<()> {
+    if !is_version_name(&record.file) || record.file.contains('/') {
+        return Err(anyhow!("bad version file name {:?}", record.file));
+    }
+    if !is_lineage_id(&record.hash) {
+        return Err(anyhow!(
+            "bad hash {:?} for version {}",
+            record.hash,
+            record.file
+        ));
+    }
+    Ok(())
+}
+
+fn import_histories(
+    cfg: &Config,
+    root: &Path,
+    staging: &Path,
+    index: Index,
+    maps: &[PrefixMap],
+    uid: u32,
+    gid: u32,
+) -> Result<()> {
+    let blobs = root.join(BLOBS_DIR);
+    create_owned_dir(&blobs, uid, gid)?;
+    let (mut histories, mut versions) = (0, 0);
+    for exported in index.histories {
+        let stem = rewrite(&exported.stem, maps);
+        if !stem.is_absolute() {
+            eprintln!(
+                "morph-bang: skipping history of {}: not an absolute path",
+                stem.display()
+            );
+            continue;
+        }
+        let dir = stem_version_dir(cfg, &stem, uid)?;
+        ensure_version_paths_owned(&dir, uid, gid)?;
+        let mut manifest = Manifest::load(&dir)?;
+        let mut added = 0;
+        for mut record in exported.versions {
+            let version = dir.join(&record.file);
+            if version.exists() || manifest.record(&version).is_some() {
+                continue;
+            }
+            let blob = adopt_blob(
+                &blobs,
+                &staging.join(archived_blob(&record)),
+                &record,
+                uid,
+                gid,
+            )
+            .with_context(|| format!("failed to import {}", record.file))?;
+            if fs::hard_link(&blob, &version).is_err() {
+                fs::copy(&blob, &version)?;
+                chown_path(&version, uid, gid)?;
+            }
+            record.source_path = rewrite(&record.source_path, maps);
+            manifest.versions.push(record);
+            added += 1;
+        }
+        manifest.versions.sort_by(|a, b| a.file.cmp(&b.file));
+        manifest.stem = stem;
+        manifest.uid = uid;
+        manifest.save(&dir, uid, gid)?;
+
+        if exported.stem == manifest.stem {
+            println!("{}: {added} versions", manifest.stem.display());
+        } else {
+            println!(
+                "{} -> {}: {added} versions",
+                exported.stem.display(),
+                manifest.stem.display()
+            );
+        }
+        histories += 1;
+        versions += added;
+    }
+    println!("Imported {versions} versions into {histories} histories");
+    Ok(())
+}
+
+/// Moves an unpacked blob into the store, unless the store already holds
+/// those contents. A new blob is checked against the hash it is filed under
+/// first, so a damaged archive cannot shadow other contents. Only regular
+/// files are taken: an archive entry that unpacked as a symlink would have
+/// its target hashed and moved into the store.
+fn adopt_blob(
+    blobs: &Path,
+    unpacked: &Path,
+    record: &VersionRecord,
+    uid: u32,
+    gid: u32,
+) -> Result<PathBuf> {
+    if !fs::symlink_metadata(unpacked)?.file_type().is_file() {
+        return Err(anyhow!("blob {} is not a regular file", record.hash));
+    }
+    let dest = blob_path(blobs, &record.hash, is_compressed(unpacked));
+    if dest.is_file() {
+        return Ok(dest);
+    }
+    let hash = hash_version(unpacked)?;
+    if hash.to_hex().as_str() != record.hash {
+        return Err(anyhow!("contents do not match hash {}", record.hash));
+    }
+    create_owned_dir(dest.parent().unwrap_or(blobs), uid, gid)?;
+    chown_path(unpacked, uid, gid)?;
+    fs::rename(unpacked, &dest)?;
+    Ok(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn maps(specs: &[&str]) -> Vec<PrefixMap> {
+        specs.iter().map(|s| PrefixMap::parse(s).unwrap()).collect()
+    }
+
+    fn record(file: &str, hash: &str) -> VersionRecord {
+        VersionRecord {
+            file: file.to_string(),
+            hash: hash.to_string(),
+            ..VersionRecord::default()
+        }
+    }
+
+    #[test]
+    fn rewrites_by_the_longest_matching_prefix() {
+        let maps = maps(&["/home/ana=/home/ana.new", "/home/ana/photos=/srv/photos"]);
+        let rewrite = |path: &str| rewrite(Path::new(path), &maps);
+        assert_eq!(rewrite("/home/ana/notes"), Path::new("/home/ana.new/notes"));
+        assert_eq!(
+            rewrite("/home/ana/photos/cat"),
+            Path::new("/srv/photos/cat")
+        );
+        assert_eq!(rewrite("/home/ana"), Path::new("/home/ana.new"));
+        assert_eq!(
+            rewrite("/home/anabel/notes"),
+            Path::new("/home/anabel/notes")
+        );
+        assert!(PrefixMap::parse("home/ana=/home/ana.new").is_err());
+        assert!(PrefixMap::parse("/home/ana").is_err());
+    }
+
+    #[test]
+    fn rejects_records_that_leave_their_directory_or_name_no_hash() {
+        let hash = "0".repeat(64);
+        let file = "00000000000000000001-0000.png";
+        assert!(check_record(&record(file, &hash)).is_ok());
+        for bad in [
+            "00000000000000000001/../../../.bashrc",
+            "../00000000000000000001-0000.png",
+            "photo.png",
+        ] {
+            assert!(check_record(&record(bad, &hash)).is_err(), "{bad}");
+        }
+        for bad in [
+            "../../etc",
+            &"A".repeat(64),
+            &"0".repeat(63),
+            &"g".repeat(64),
+        ] {
+            assert!(check_record(&record(file, bad)).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn adopts_only_blobs_that_match_their_hash() {
+        let dir = TempDir::new("adopt-blob");
+        let blobs = dir.join(BLOBS_DIR);
+        fs::create_dir(&blobs).unwrap();
+        let (uid, gid) = (Uid::current().as_raw(), Gid::current().as_raw());
+        let unpacked = dir.join("unpacked");
+        let hash = blake3::hash(b"pixels").to_hex().to_string();
+
+        fs::write(&unpacked, "tampered").unwrap();
+        let version = record("00000000000000000001-0000.png", &hash);
+        assert!(adopt_blob(&blobs, &unpacked, &version, uid, gid).is_err());
+        assert!(!blob_path(&blobs, &hash, false).exists());
+
+        fs::write(&unpacked, "pixels").unwrap();
+        let blob = adopt_blob(&blobs, &unpacked, &version, uid, gid).unwrap();
+        assert_eq!(blob, blob_path(&blobs, &hash, false));
+        assert_eq!(fs::read(&blob).unwrap(), b"pixels");
+        assert!(!unpacked.exists());
+
+        // Contents already in the store are kept, whatever was unpacked.
+        fs::write(&unpacked, "tampered").unwrap();
+        assert_eq!(
+            adopt_blob(&blobs, &unpacked, &version, uid, gid).unwrap(),
+            blob
+        );
+        assert_eq!(fs::read(&blob).unwrap(), b"pixels");
+    }
+
+    #[test]
+    fn refuses_a_blob_that_unpacked_as_a_symlink() {
+        let dir = TempDir::new("adopt-symlink");
+        let blobs = dir.join(BLOBS_DIR);
+        fs::create_dir(&blobs).unwrap();
+        let (uid, gid) = (Uid::current().as_raw(), Gid::current().as_raw());
+        let target = dir.join("elsewhere");
+        fs::write(&target, "pixels").unwrap();
+        let unpacked = dir.join("unpacked");
+        std::os::unix::fs::symlink(&target, &unpacked).unwrap();
+        let hash = blake3::hash(b"pixels").to_hex().to_string();
+        let version = record("00000000000000000001-0000.png", &hash);
+
+        assert!(adopt_blob(&blobs, &unpacked, &version, uid, gid).is_err());
+        assert!(!blob_path(&blobs, &hash, false).exists());
+        assert!(fs::symlink_metadata(&unpacked).unwrap().is_symlink());
+    }
+}
@@ -0,0 +1,791 @@
+use crate::options::ConvertOptions;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use toml::{Table, Value};
+
+pub const CONFIG_PATH: &str = "/etc/morph-bang/config.toml";
+pub const USER_CONFIG_PATH: &str = ".config/morph-bang/config.toml";
+/// Trigger names with a built-in meaning, which presets can neither take
+/// as their name nor convert to.
+pub const RESERVED_TARGETS: &[&str] = &["info", "undo"];
+
+const PDF_PAGE_SIZES: &[&str] = &[
+    "letter",
+    "legal",
+    "tabloid",
+    "ledger",
+    "executive",
+    "a3",
+    "a4",
+    "a5",
+    "b4",
+    "b5",
+];
+const PDF_PROFILES: &[&str] = &["/screen", "/ebook", "/printer", "/prepress", "/default"];
+
+/// Daemon settings read from `/etc/morph-bang/config.toml`.
+///
+/// Every key is optional; a missing file or section falls back to the
+/// built-in defaults below.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub watch: WatchConfig,
+    pub pdf: PdfConfig,
+    pub engines: EngineConfig,
+    pub versions: VersionConfig,
+    pub defaults: DefaultsConfig,
+    pub presets: BTreeMap<String, Preset>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchConfig {
+    pub roots: Vec<PathBuf>,
+    pub exclude: Vec<PathBuf>,
+    pub lock_ttl_secs: u64,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            roots: vec![PathBuf::from("/home")],
+            exclude: Vec::new(),
+            lock_ttl_secs: 2,
+        }
+    }
+}
+
+impl WatchConfig {
+    pub fn lock_ttl(&self) -> Duration {
+        Duration::from_secs(self.lock_ttl_secs)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PdfConfig {
+    pub page_size: String,
+    pub profile: String,
+}
+
+impl Default for PdfConfig {
+    fn default() -> Self {
+        Self {
+            page_size: "letter".to_string(),
+            profile: "/ebook".to_string(),
+        }
+    }
+}
+
+/// Executables used for each conversion step; bare names are looked up in `PATH`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    pub file: PathBuf,
+    pub vips: PathBuf,
+    pub magick: PathBuf,
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+    pub pandoc: PathBuf,
+    pub pdfinfo: PathBuf,
+    pub pdfunite: PathBuf,
+    pub gs: PathBuf,
+    pub vipsheader: PathBuf,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from("file"),
+            vips: PathBuf::from("vips"),
+            magick: PathBuf::from("magick"),
+            ffmpeg: PathBuf::from("ffmpeg"),
+            ffprobe: PathBuf::from("ffprobe"),
+            pandoc: PathBuf::from("pandoc"),
+            pdfinfo: PathBuf::from("pdfinfo"),
+            pdfunite: PathBuf::from("pdfunite"),
+            gs: PathBuf::from("gs"),
+            vipsheader: PathBuf::from("vipsheader"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VersionConfig {
+    /// Store location, relative to each user's home directory.
+    pub store_dir: PathBuf,
+    /// Versions kept per file; older ones are removed first.
+    pub max_versions: Option<usize>,
+    /// Versions older than this many days are removed.
+    pub max_age_days: Option<u64>,
+    /// Upper bound on each user's store, e.g. `"20G"`; the oldest versions
+    /// across all files are removed until it fits.
+    pub max_total_size: Option<String>,
+    /// How often the daemon applies the limits above.
+    pub gc_interval_mins: u64,
+    /// Store and restore versions as reflinks where the filesystem supports
+    /// them, so large files are not copied byte by byte.
+    pub reflink: bool,
+    /// Compress text and uncompressed media with zstd when stored.
+    pub compress: bool,
+}
+
+impl Default for VersionConfig {
+    fn default() -> Self {
+        Self {
+            store_dir: PathBuf::from(".local/share/morph-bang/versions"),
+            max_versions: None,
+            max_age_days: None,
+            max_total_size: None,
+            gc_interval_mins: 60,
+            reflink: true,
+            compress: false,
+        }
+    }
+}
+
+impl VersionConfig {
+    /// Whether any retention limit is set. Without one, nothing is removed.
+    pub fn has_retention(&self) -> bool {
+        self.max_versions.is_some() || self.max_age_days.is_some() || self.max_total_size.is_some()
+    }
+
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age_days.map(|d| Duration::from_secs(d * 86_400))
+    }
+
+    pub fn max_total_bytes(&self) -> Option<u64> {
+        self.max_total_size.as_deref().and_then(parse_size)
+    }
+
+    pub fn gc_interval(&self) -> Duration {
+        Duration::from_secs(self.gc_interval_mins * 60)
+    }
+}
+
+/// System-wide defaults for the settings users may override.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefaultsConfig {
+    pub safe_mode: bool,
+    pub notifications: Verbosity,
+    /// Also write `.!?<ext>` previews to a `<name>.preview.txt` sidecar.
+    pub preview_sidecar: bool,
+    pub lossy: LossyPolicy,
+    pub quality: QualityConfig,
+}
+
+impl Default for DefaultsConfig {
+    fn default() -> Self {
+        Self {
+            safe_mode: true,
+            notifications: Verbosity::All,
+            preview_sidecar: false,
+            lossy: LossyPolicy::Warn,
+            quality: QualityConfig::default(),
+        }
+    }
+}
+
+/// A named recipe selected with `.!<name>`, e.g. `[presets.web]` with
+/// `target = "webp"` and `options = "q80,w1920"`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    pub target: String,
+    #[serde(default)]
+    pub options: String,
+}
+
+impl Preset {
+    /// The preset's options. They are checked when the config loads, but a
+    /// preset can also be built or changed afterwards, so a bad one is still
+    /// reported rather than silently dropped.
+    pub fn options(&self) -> Result<ConvertOptions> {
+        if self.options.is_empty() {
+            return Ok(ConvertOptions::default());
+        }
+        ConvertOptions::parse(&self.options)
+    }
+
+    fn validate(&mut self, key: &str) -> Result<()> {
+        self.target = self.target.to_lowercase();
+        if self.target.is_empty() || !self.target.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(anyhow!(
+                "{key}.target {:?} must be a plain extension",
+                self.target
+            ));
+        }
+        if RESERVED_TARGETS.contains(&self.target.as_str()) {
+            return Err(anyhow!(
+                "{key}.target {:?} is a built-in trigger, not a format",
+                self.target
+            ));
+        }
+        self.options = self.options.to_lowercase();
+        self.options().with_context(|| format!("{key}.options"))?;
+        Ok(())
+    }
+}
+
+fn validate_presets(presets: &mut BTreeMap<String, Preset>, section: &str) -> Result<()> {
+    let names: Vec<String> = presets.keys().cloned().collect();
+    for name in names {
+        let valid_name = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_');
+        if !valid_name {
+            return Err(anyhow!(
+                "{section}.{name:?}: preset names may only use a-z, 0-9, '-' and '_'"
+            ));
+        }
+        if RESERVED_TARGETS.contains(&name.as_str()) {
+            return Err(anyhow!("{section}.{name}: {name:?} is a built-in trigger"));
+        }
+        if let Some(preset) = presets.get_mut(&name) {
+            preset.validate(&format!("{section}.{name}"))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    All,
+    Errors,
+    None,
+}
+
+/// What a safe conversion does when it re-encodes one lossy format into
+/// another, e.g. MP3 to OGG. `.!!<ext>` always converts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LossyPolicy {
+    Allow,
+    /// Converts and says so in the notification.
+    Warn,
+    /// Converts only when the trigger names options, e.g. `.!webp@q90`.
+    Confirm,
+}
+
+/// Encoder quality used when a conversion re-encodes; unset keys leave the
+/// engine defaults in place.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QualityConfig {
+    /// Lossy image quality, 1-100.
+    pub image: Option<u8>,
+    /// Audio bitrate such as `"192k"`.
+    pub audio_bitrate: Option<String>,
+    /// Video constant rate factor, 0-51.
+    pub video_crf: Option<u8>,
+}
+
+impl QualityConfig {
+    fn validate(&self, section: &str) -> Result<()> {
+        if let Some(q) = self.image {
+            if !(1..=100).contains(&q) {
+                return Err(anyhow!("{section}.image must be between 1 and 100"));
+            }
+        }
+        if let Some(bitrate) = &self.audio_bitrate {
+            if !is_bitrate(bitrate) {
+                return Err(anyhow!(
+                    "{section}.audio_bitrate {bitrate:?} must look like \"192k\""
+                ));
+            }
+        }
+        if let Some(crf) = self.video_crf {
+            if crf > 51 {
+                return Err(anyhow!("{section}.video_crf must be between 0 and 51"));
+            }
+        }
+        Ok(())
+    }
+
+    fn overlay(&mut self, other: &QualityConfig) {
+        if other.image.is_some() {
+            self.image = other.image;
+        }
+        if other.audio_bitrate.is_some() {
+            self.audio_bitrate = other.audio_bitrate.clone();
+        }
+        if other.video_crf.is_some() {
+            self.video_crf = other.video_crf;
+        }
+    }
+}
+
+/// Per-user overrides read from `~/.config/morph-bang/config.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    pub safe_mode: Option<bool>,
+    pub notifications: Option<Verbosity>,
+    pub preview_sidecar: Option<bool>,
+    pub lossy: Option<LossyPolicy>,
+    pub pdf: UserPdfConfig,
+    pub quality: QualityConfig,
+    pub presets: BTreeMap<String, Preset>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserPdfConfig {
+    pub page_size: Option<String>,
+    pub profile: Option<String>,
+}
+
+/// Effective settings for one user: built-in defaults, then the system
+/// config, then the user's own file.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub safe_mode: bool,
+    pub notifications: Verbosity,
+    pub preview_sidecar: bool,
+    pub lossy: LossyPolicy,
+    pub pdf: PdfConfig,
+    pub quality: QualityConfig,
+    pub presets: BTreeMap<String, Preset>,
+}
+
+impl UserConfig {
+    /// Loads `path`, returning `None` when it does not exist. The file must be
+    /// owned by `uid` so the daemon never parses somebody else's file on their behalf.
+    /// It is opened once, without following a symlink, and checked and read
+    /// through that handle, so it cannot be swapped for another file between
+    /// the check and the read.
+    pub fn load(path: &Path, uid: u32) -> Result<Option<Self>> {
+        let opened = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_NOFOLLOW | nix::libc::O_NONBLOCK)
+            .open(path);
+        let mut file = match opened {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        let meta = file
+            .metadata()
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if meta.uid() != uid || !meta.is_file() {
+            return Err(anyhow!(
+                "{} is not a file owned by uid {uid}, ignoring it",
+                path.display()
+            ));
+        }
+        let mut raw = String::new();
+        file.read_to_string(&mut raw)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let mut config: Self = parse_toml(&raw)
+            .with_context(|| format!("invalid configuration in {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid configuration in {}", path.display()))?;
+        Ok(Some(config))
+    }
+
+    fn validate(&mut self) -> Result<()> {
+        if let Some(size) = &mut self.pdf.page_size {
+            validate_page_size(size, "pdf.page_size")?;
+        }
+        if let Some(profile) = &mut self.pdf.profile {
+            validate_profile(profile, "pdf.profile")?;
+        }
+        self.quality.validate("quality")?;
+        validate_presets(&mut self.presets, "presets")
+    }
+}
+
+impl Config {
+    /// Layers `user` on top of the system defaults.
+    pub fn settings(&self, user: Option<&UserConfig>) -> Settings {
+        let mut settings = Settings {
+            safe_mode: self.defaults.safe_mode,
+            notifications: self.defaults.notifications,
+            preview_sidecar: self.defaults.preview_sidecar,
+            lossy: self.defaults.lossy,
+            pdf: self.pdf.clone(),
+            quality: self.defaults.quality.clone(),
+            presets: self.presets.clone(),
+        };
+        let Some(user) = user else {
+            return settings;
+        };
+        if let Some(v) = user.safe_mode {
+            settings.safe_mode = v;
+        }
+        if let Some(v) = user.notifications {
+            settings.notifications = v;
+        }
+        if let Some(v) = user.preview_sidecar {
+            settings.preview_sidecar = v;
+        }
+        if let Some(v) = user.lossy {
+            settings.lossy = v;
+        }
+        if let Some(v) = &user.pdf.page_size {
+            settings.pdf.page_size = v.clone();
+        }
+        if let Some(v) = &user.pdf.profile {
+            settings.pdf.profile = v.clone();
+        }
+        settings.quality.overlay(&user.quality);
+        for (name, preset) in &user.presets {
+            settings.presets.insert(name.clone(), preset.clone());
+        }
+        settings
+    }
+
+    /// Loads and validates `path`. A missing file yields the defaults; a file
+    /// that exists but cannot be parsed or validated is an error.
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = match fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        let mut config: Self = parse_toml(&raw)
+            .with_context(|| format!("invalid configuration in {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid configuration in {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Human-readable list of the settings that differ between `self` and
+    /// `new`, by their dotted keys as written in the file.
+    pub fn describe_changes(&self, new: &Config) -> Vec<String> {
+        let mut changes = Vec::new();
+        match (Value::try_from(self), Value::try_from(new)) {
+            (Ok(old), Ok(new)) => diff(&mut changes, "", Some(&old), Some(&new)),
+            (Err(err), _) | (_, Err(err)) => changes.push(format!("cannot be compared: {err}")),
+        }
+        changes
+    }
+
+    fn validate(&mut self) -> Result<()> {
+        if self.watch.roots.is_empty() {
+            return Err(anyhow!("watch.roots must list at least one directory"));
+        }
+        for root in &self.watch.roots {
+            if !root.is_absolute() {
+                return Err(anyhow!(
+                    "watch.roots entry {} must be absolute",
+                    root.display()
+                ));
+            }
+        }
+        for path in &self.watch.exclude {
+            if !path.is_absolute() {
+                return Err(anyhow!(
+                    "watch.exclude entry {} must be absolute",
+                    path.display()
+                ));
+            }
+        }
+        if self.watch.lock_ttl_secs == 0 {
+            return Err(anyhow!("watch.lock_ttl_secs must be at least 1"));
+        }
+
+        validate_page_size(&mut self.pdf.page_size, "pdf.page_size")?;
+        validate_profile(&mut self.pdf.profile, "pdf.profile")?;
+        self.defaults.quality.validate("defaults.quality")?;
+        validate_presets(&mut self.presets, "presets")?;
+
+        let engines = [
+            ("file", &self.engines.file),
+            ("vips", &self.engines.vips),
+            ("magick", &self.engines.magick),
+            ("ffmpeg", &self.engines.ffmpeg),
+            ("ffprobe", &self.engines.ffprobe),
+            ("pandoc", &self.engines.pandoc),
+            ("pdfinfo", &self.engines.pdfinfo),
+            ("pdfunite", &self.engines.pdfunite),
+            ("gs", &self.engines.gs),
+            ("vipsheader", &self.engines.vipsheader),
+        ];
+        for (name, path) in engines {
+            if path.as_os_str().is_empty() {
+                return Err(anyhow!("engines.{name} must not be empty"));
+            }
+        }
+
+        let store_dir = &self.versions.store_dir;
+        if store_dir.as_os_str().is_empty()
+            || !store_dir
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(anyhow!(
+                "versions.store_dir {} must be a relative path inside the home directory",
+                store_dir.display()
+            ));
+        }
+        if self.versions.max_versions == Some(0) {
+            return Err(anyhow!("versions.max_versions must be at least 1"));
+        }
+        if let Some(size) = &self.versions.max_total_size {
+            if parse_size(size).is_none() {
+                return Err(anyhow!(
+                    "versions.max_total_size {size:?} must be a size such as \"500M\" or \"20G\""
+                ));
+            }
+        }
+        if self.versions.gc_interval_mins == 0 {
+            return Err(anyhow!("versions.gc_interval_mins must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+fn validate_page_size(size: &mut String, key: &str) -> Result<()> {
+    *size = size.to_lowercase();
+    if !PDF_PAGE_SIZES.contains(&size.as_str()) {
+        return Err(anyhow!(
+            "{key} {size:?} is not one of {}",
+            PDF_PAGE_SIZES.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn validate_profile(profile: &mut String, key: &str) -> Result<()> {
+    if !profile.starts_with('/') {
+        profile.insert(0, '/');
+    }
+    if !PDF_PROFILES.contains(&profile.as_str()) {
+        return Err(anyhow!(
+            "{key} {profile:?} is not one of {}",
+            PDF_PROFILES.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Parses a config file. Errors give the line and what is wrong, but not
+/// the text of the line, which would end up in notifications.
+fn parse_toml<T: serde::de::DeserializeOwned>(raw: &str) -> Result<T> {
+    toml::from_str(raw).map_err(|err| {
+        let message = err.message().trim_end();
+        match err.span() {
+            Some(span) => {
+                let before = raw.get(..span.start).unwrap_or(raw);
+                let line = before.matches('\n').count() + 1;
+                anyhow!("line {line}: {message}")
+            }
+            None => anyhow!("{message}"),
+        }
+    })
+}
+
+/// Reads a size such as `20G`, `512MB` or `64KiB`; units are binary.
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (digits, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value, ""),
+    };
+    let unit = unit.to_ascii_uppercase();
+    let unit = unit.trim_end_matches('B');
+    let shift = match unit.strip_suffix('I').unwrap_or(unit) {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// `bytes` in binary units, the ones `max_total_size` is read in.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for u in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = u;
+    }
+    format!("{value:.1} {unit}")
+}
+
+fn is_bitrate(value: &str) -> bool {
+    value
+        .strip_suffix(['k', 'K'])
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Adds a line for every value that differs below `key`; lists compare by
+/// their entries, so reordering one is not a change.
+fn diff(changes: &mut Vec<String>, key: &str, old: Option<&Value>, new: Option<&Value>) {
+    if old == new {
+        return;
+    }
+    match (old, new) {
+        (Some(Value::Array(old)), Some(Value::Array(new))) => {
+            for value in new.iter().filter(|v| !old.contains(v)) {
+                changes.push(format!("{key}: added {}", show_entry(value)));
+            }
+            for value in old.iter().filter(|v| !new.contains(v)) {
+                changes.push(format!("{key}: removed {}", show_entry(value)));
+            }
+        }
+        (None | Some(Value::Table(_)), None | Some(Value::Table(_))) => {
+            let empty = Table::new();
+            let old = old.and_then(Value::as_table).unwrap_or(&empty);
+            let new = new.and_then(Value::as_table).unwrap_or(&empty);
+            let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for name in names {
+                let child = if key.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{key}.{name}")
+                };
+                diff(changes, &child, old.get(name), new.get(name));
+            }
+        }
+        _ => {
+            let show = |value: Option<&Value>| value.map_or("unset".to_string(), Value::to_string);
+            changes.push(format!("{key}: {} -> {}", show(old), show(new)));
+        }
+    }
+}
+
+fn show_entry(value: &Value) -> String {
+    value
+        .as_str()
+        .map_or_else(|| value.to_string(), str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use nix::unistd::Uid;
+
+    #[test]
+    fn parses_sizes_in_binary_units() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("4K"), Some(4 << 10));
+        assert_eq!(parse_size("20G"), Some(20 << 30));
+        assert_eq!(parse_size("1tb"), Some(1 << 40));
+        assert_eq!(parse_size(" 3MB "), Some(3 << 20));
+        assert_eq!(parse_size("64KiB"), Some(64 << 10));
+    }
+
+    #[test]
+    fn formats_sizes_in_the_units_they_are_read_in() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(4 << 10), "4.0 KiB");
+        assert_eq!(format_size(48 << 20 | 200 << 10), "48.2 MiB");
+        assert_eq!(format_size(20 << 30), "20.0 GiB");
+        assert_eq!(format_size(3 << 40), "3.0 TiB");
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("10X"), None);
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+
+    #[test]
+    fn describes_changes_by_their_dotted_keys() {
+        let old: Config = parse_toml(
+            "[watch]\nroots = [\"/home\", \"/srv\"]\n[presets.web]\ntarget = \"webp\"\n",
+        )
+        .unwrap();
+        let new: Config = parse_toml(
+            "[watch]\nroots = [\"/srv\", \"/data\"]\n[versions]\nmax_versions = 5\n\
+             [defaults]\nlossy = \"confirm\"\n[presets.web]\ntarget = \"avif\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            old.describe_changes(&new),
+            [
+                "defaults.lossy: \"warn\" -> \"confirm\"",
+                "presets.web.target: \"webp\" -> \"avif\"",
+                "versions.max_versions: unset -> 5",
+                "watch.roots: added /data",
+                "watch.roots: removed /home",
+            ]
+        );
+        assert!(new.describe_changes(&new.clone()).is_empty());
+    }
+
+    #[test]
+    fn loads_a_user_config_owned_by_the_user() {
+        let dir = TempDir::new("config-user");
+        let path = dir.join("config.toml");
+        fs::write(&path, "safe_mode = false\n").unwrap();
+        let user = UserConfig::load(&path, Uid::current().as_raw()).unwrap();
+        assert_eq!(user.unwrap().safe_mode, Some(false));
+        assert!(UserConfig::load(&dir.join("missing.toml"), 0)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn refuses_a_user_config_that_is_a_symlink_or_not_a_file() {
+        let dir = TempDir::new("config-symlink");
+        let secret = dir.join("secret");
+        fs::write(&secret, "password = \"hunter2\"\n").unwrap();
+        let link = dir.join("config.toml");
+        std::os::unix::fs::symlink(&secret, &link).unwrap();
+        let uid = Uid::current().as_raw();
+        assert!(UserConfig::load(&link, uid).is_err());
+
+        let fifo = dir.join("fifo.toml");
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU).unwrap();
+        assert!(UserConfig::load(&fifo, uid).is_err());
+    }
+
+    #[test]
+    fn reports_syntax_errors_without_the_offending_line() {
+        let dir = TempDir::new("config-syntax");
+        let path = dir.join("config.toml");
+        fs::write(&path, "safe_mode = false\nsecret-value here\n").unwrap();
+        let err = UserConfig::load(&path, Uid::current().as_raw()).unwrap_err();
+        let err = format!("{err:#}");
+        assert!(err.contains("line 2"), "{err}");
+        assert!(!err.contains("secret-value"), "{err}");
+    }
+
+    #[test]
+    fn rejects_presets_named_or_targeting_built_in_triggers() {
+        let load = |raw: &str| -> Result<Config> {
+            let mut config: Config = parse_toml(raw)?;
+            config.validate()?;
+            Ok(config)
+        };
+        assert!(load("[presets.web]\ntarget = \"WebP\"\noptions = \"Q80\"\n").is_ok());
+        let err = load("[presets.undo]\ntarget = \"webp\"\n").unwrap_err();
+        assert!(format!("{err:#}").contains("built-in"), "{err:#}");
+        let err = load("[presets.report]\ntarget = \"info\"\n").unwrap_err();
+        assert!(format!("{err:#}").contains("built-in"), "{err:#}");
+    }
+}
@@ -0,0 +1,329 @@
+use crate::config::Config;
+use crate::formats::{
+    can_copy_streams, has_image_quality, is_audio_output, is_doc_output, is_image_output,
+    is_media_output, pandoc_from_ext,
+};
+use crate::options::ConvertOptions;
+use crate::{chown_path, display_name};
+use anyhow::{anyhow, Context, Result};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const VIPS_MAX_DIMENSION: u32 = 10_000_000;
+
+/// Which conversion backend `morph_engine` uses for a source/target pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Image,
+    Media,
+    Document,
+}
+
+/// Creates a private scratch directory under the system temp dir, owned by
+/// the user the work is done for. `kind` only labels the directory name.
+pub fn create_workspace(kind: &str, input: &Path, uid: u32, gid: u32) -> Result<PathBuf> {
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("clock error")?
+        .as_nanos();
+    let pid = std::process::id();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(input.as_os_str().as_bytes());
+    let tag = hasher.finalize().to_hex();
+    let path = std::env::temp_dir().join(format!("morph-bang-{kind}-{pid}-{ts}-{}", &tag[..12]));
+    fs::create_dir_all(&path)?;
+    chown_path(&path, uid, gid)?;
+    Ok(path)
+}
+
+pub fn engine_for(mime: &str, target_ext: &str) -> Option<Engine> {
+    if mime.starts_with("image/") || mime == "application/pdf" || mime == "application/postscript" {
+        return Some(Engine::Image);
+    }
+    if mime.starts_with("video/") || mime.starts_with("audio/") {
+        return Some(Engine::Media);
+    }
+    if is_doc_output(target_ext) {
+        return Some(Engine::Document);
+    }
+    None
+}
+
+/// Rejects options the selected engine cannot honour for this conversion.
+pub fn check_options(
+    opts: &ConvertOptions,
+    engine: Engine,
+    mime: &str,
+    source_ext: &str,
+    target_ext: &str,
+) -> Result<()> {
+    let video = engine == Engine::Media && mime.starts_with("video/");
+    let media_target = is_media_output(target_ext) && !is_image_output(target_ext);
+    let image_target = engine == Engine::Image || (video && !media_target);
+    let unsupported = |what: &str| {
+        Err(anyhow!(
+            "{what} is not supported when converting {mime} to {}",
+            target_ext.to_uppercase()
+        ))
+    };
+
+    if opts.quality.is_some() && !(engine == Engine::Image && has_image_quality(target_ext)) {
+        return unsupported("quality (q)");
+    }
+    if opts.wants_resize() && !(image_target || (video && !is_audio_output(target_ext))) {
+        return unsupported("resizing (w/h)");
+    }
+    if opts.audio_bitrate.is_some() && !(engine == Engine::Media && media_target) {
+        return unsupported("audio bitrate");
+    }
+    if opts.video_crf.is_some() && !(video && media_target && !is_audio_output(target_ext)) {
+        return unsupported("crf");
+    }
+    if opts.pages.is_some() && !(engine == Engine::Image && source_ext == "pdf") {
+        return unsupported("page ranges (p)");
+    }
+    Ok(())
+}
+
+pub fn morph_engine(
+    cfg: &Config,
+    input: &Path,
+    out: &Path,
+    target_ext: &str,
+    source_ext: &str,
+    mime: &str,
+    opts: &ConvertOptions,
+) -> Result<i32> {
+    match engine_for(mime, target_ext) {
+        Some(Engine::Image) => morph_image(cfg, input, out, target_ext, source_ext, opts),
+        Some(Engine::Media) => morph_media(cfg, input, out, target_ext, source_ext, mime, opts),
+        Some(Engine::Document) => {
+            let from = pandoc_from_ext(source_ext);
+            let mut cmd = Command::new(&cfg.engines.pandoc);
+            cmd.arg("-f").arg(from).arg(input).arg("-s");
+            if target_ext == "pdf" {
+                cmd.arg("--pdf-engine=xelatex");
+            } else {
+                cmd.arg("--mathjax");
+            }
+            cmd.arg("-o").arg(out);
+            run_cmd(&mut cmd)?;
+            Ok(0)
+        }
+        None => Err(anyhow!("unsupported conversion")),
+    }
+}
+
+fn morph_image(
+    cfg: &Config,
+    input: &Path,
+    out: &Path,
+    target_ext: &str,
+    source_ext: &str,
+    opts: &ConvertOptions,
+) -> Result<i32> {
+    if source_ext == "pdf" {
+        let pages = pdf_pages(cfg, input)
+            .ok_or_else(|| anyhow!("could not count the pages of {}", display_name(input)))?;
+        let (first, last) = opts.pages.unwrap_or((1, pages));
+        if first > pages {
+            return Err(anyhow!(
+                "page {first} is out of range, document has {pages} pages"
+            ));
+        }
+        let last = last.min(pages);
+        if last > first {
+            let dir_path = input.with_extension("");
+            fs::create_dir_all(&dir_path)?;
+            let input_meta = fs::metadata(input)?;
+            chown_path(&dir_path, input_meta.uid(), input_meta.gid())?;
+            let mut success = false;
+            for page in first..=last {
+                let page_file = dir_path.join(format!("{:03}.{}", page, target_ext));
+                let in_arg = format!("{}[dpi=300,page={}]", input.display(), page - 1);
+                if vips_convert(cfg, &in_arg, &page_file, target_ext, opts).is_ok() {
+                    if copy_owner_and_perms(input, &page_file).is_ok() {
+                        success = true;
+                    } else {
+                        let _ = fs::remove_file(&page_file);
+                    }
+                }
+            }
+            if success {
+                return Ok(2);
+            }
+            if opts.pages.is_some() {
+                let _ = fs::remove_dir(&dir_path);
+                return Err(anyhow!("none of pages {first}-{last} could be converted"));
+            }
+        } else if opts.pages.is_some() {
+            let in_arg = format!("{}[dpi=300,page={}]", input.display(), first - 1);
+            vips_convert(cfg, &in_arg, out, target_ext, opts)?;
+            return Ok(0);
+        }
+    }
+    if matches!(source_ext, "svg" | "svgz" | "eps" | "ai" | "pdf") {
+        let in_arg = format!("{}[dpi=300,scale=2]", input.display());
+        if vips_convert(cfg, &in_arg, out, target_ext, opts).is_ok() {
+            return Ok(0);
+        }
+    }
+    vips_convert(cfg, input, out, target_ext, opts)?;
+    Ok(0)
+}
+
+fn morph_media(
+    cfg: &Config,
+    input: &Path,
+    out: &Path,
+    target_ext: &str,
+    source_ext: &str,
+    mime: &str,
+    opts: &ConvertOptions,
+) -> Result<i32> {
+    if copies_streams(source_ext, target_ext, opts)
+        && run_cmd(
+            Command::new(&cfg.engines.ffmpeg)
+                .arg("-y")
+                .arg("-i")
+                .arg(input)
+                .arg("-c")
+                .arg("copy")
+                .arg("-map")
+                .arg("0")
+                .arg("-hide_banner")
+                .arg("-loglevel")
+                .arg("error")
+                .arg(out),
+        )
+        .is_ok()
+    {
+        return Ok(0);
+    }
+
+    let video_target = mime.starts_with("video/") && !is_audio_output(target_ext);
+    let mut cmd = Command::new(&cfg.engines.ffmpeg);
+    cmd.arg("-y").arg("-i").arg(input);
+    if let Some(bitrate) = &opts.audio_bitrate {
+        if is_media_output(target_ext) {
+            cmd.arg("-b:a").arg(bitrate);
+        }
+    }
+    if let Some(crf) = opts.video_crf {
+        if video_target && is_media_output(target_ext) {
+            cmd.arg("-crf").arg(crf.to_string());
+        }
+    }
+    if video_target && opts.wants_resize() {
+        let w = opts
+            .max_width
+            .map_or("iw".to_string(), |w| format!("min({w}\\,iw)"));
+        let h = opts
+            .max_height
+            .map_or("ih".to_string(), |h| format!("min({h}\\,ih)"));
+        cmd.arg("-vf").arg(format!(
+            "scale=w={w}:h={h}:force_original_aspect_ratio=decrease:force_divisible_by=2"
+        ));
+    }
+    cmd.arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg(out);
+    run_cmd(&mut cmd)?;
+    Ok(0)
+}
+
+/// Whether ffmpeg copies the streams of `from_ext` into the `to_ext`
+/// container rather than re-encoding them: the options ask for no encoding
+/// and the container takes the codecs the source usually holds.
+pub fn copies_streams(from_ext: &str, to_ext: &str, opts: &ConvertOptions) -> bool {
+    !opts.reencode && !is_image_output(to_ext) && can_copy_streams(from_ext, to_ext)
+}
+
+/// Runs `vips copy`, or `vips thumbnail` when a maximum size was requested.
+fn vips_convert(
+    cfg: &Config,
+    input: impl AsRef<OsStr>,
+    out: &Path,
+    target_ext: &str,
+    opts: &ConvertOptions,
+) -> Result<()> {
+    let out_arg: OsString = match opts.quality {
+        Some(q) if has_image_quality(target_ext) => format!("{}[Q={q}]", out.display()).into(),
+        _ => out.as_os_str().to_owned(),
+    };
+    let mut cmd = Command::new(&cfg.engines.vips);
+    if opts.wants_resize() {
+        cmd.arg("thumbnail")
+            .arg(input)
+            .arg(out_arg)
+            .arg(opts.max_width.unwrap_or(VIPS_MAX_DIMENSION).to_string());
+        if let Some(h) = opts.max_height {
+            cmd.arg("--height").arg(h.to_string());
+        }
+        cmd.arg("--size").arg("down");
+    } else {
+        cmd.arg("copy").arg(input).arg(out_arg);
+    }
+    run_cmd(&mut cmd)
+}
+
+pub fn copy_owner_and_perms(src: &Path, dst: &Path) -> Result<()> {
+    let meta = fs::metadata(src)?;
+    chown_path(dst, meta.uid(), meta.gid())?;
+    fs::set_permissions(dst, fs::Permissions::from_mode(meta.permissions().mode()))?;
+    Ok(())
+}
+
+pub fn pdf_pages(cfg: &Config, path: &Path) -> Option<u32> {
+    let out = Command::new(&cfg.engines.pdfinfo).arg(path).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let s = String::from_utf8_lossy(&out.stdout);
+    for line in s.lines() {
+        if let Some(rest) = line.strip_prefix("Pages:") {
+            return rest.trim().parse().ok();
+        }
+    }
+    None
+}
+
+pub fn run_cmd(cmd: &mut Command) -> Result<()> {
+    let out = cmd.output()?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&out.stderr).trim().to_string();
+        if stderr.is_empty() {
+            Err(anyhow!("command failed"))
+        } else {
+            Err(anyhow!("command failed: {stderr}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fake_tool, TempDir};
+
+    #[test]
+    fn fails_a_page_range_when_no_page_renders() {
+        let root = TempDir::new("engine-pages");
+        let mut cfg = Config::default();
+        cfg.engines.pdfinfo = fake_tool(&root, "pdfinfo", "echo 'Pages: 3'");
+        cfg.engines.vips = fake_tool(&root, "vips", "exit 1");
+        let input = root.join("doc.pdf");
+        fs::write(&input, "%PDF").unwrap();
+        let opts = ConvertOptions::parse("p1-2").unwrap();
+
+        let result = morph_image(&cfg, &input, &root.join("doc.png"), "png", "pdf", &opts);
+        assert!(result.is_err());
+        assert!(!root.join("doc").exists());
+    }
+}
@@ -0,0 +1,371 @@
+/// Alternative spellings of the same format, mapped to the name the format
+/// tables below use. Outputs keep the extension the user asked for; the
+/// canonical name is only used to classify it and to match history.
+const ALIASES: &[(&str, &str)] = &[
+    ("jpeg", "jpg"),
+    ("jpe", "jpg"),
+    ("jfif", "jpg"),
+    ("tif", "tiff"),
+    ("heif", "heic"),
+    ("fit", "fits"),
+    ("fts", "fits"),
+    ("mpeg", "mpg"),
+    ("aif", "aiff"),
+    ("htm", "html"),
+    ("markdown", "md"),
+    ("latex", "tex"),
+    ("asciidoc", "adoc"),
+];
+
+/// Maps an extension to its canonical spelling, e.g. `jpeg` to `jpg`.
+pub fn canonical_ext(ext: &str) -> &str {
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == ext)
+        .map_or(ext, |(_, canonical)| canonical)
+}
+
+/// Whether two extensions name the same format.
+pub fn same_format(a: &str, b: &str) -> bool {
+    canonical_ext(&a.to_lowercase()) == canonical_ext(&b.to_lowercase())
+}
+
+pub fn is_valid_target(mime: &str, ext: &str) -> bool {
+    if mime.starts_with("image/") || mime == "application/postscript" || mime == "application/pdf" {
+        return is_image_output(ext) || is_doc_output(ext);
+    }
+    if mime.starts_with("video/") {
+        return is_media_output(ext) || is_image_output(ext);
+    }
+    if mime.starts_with("audio/") {
+        return is_media_output(ext);
+    }
+    if mime.starts_with("text/")
+        || mime == "application/pdf"
+        || mime.contains("officedocument")
+        || mime.starts_with("application/epub")
+        || mime == "application/json"
+    {
+        return is_doc_output(ext);
+    }
+    false
+}
+
+pub fn is_image_output(ext: &str) -> bool {
+    matches!(
+        canonical_ext(ext),
+        "png"
+            | "jpg"
+            | "webp"
+            | "avif"
+            | "heic"
+            | "tiff"
+            | "gif"
+            | "jxl"
+            | "jp2"
+            | "j2k"
+            | "jpc"
+            | "jpt"
+            | "j2c"
+            | "hdr"
+            | "ppm"
+            | "pgm"
+            | "pbm"
+            | "pfm"
+            | "pnm"
+            | "fits"
+            | "bmp"
+            | "ico"
+            | "psd"
+            | "tga"
+            | "pcx"
+            | "pdf"
+            | "eps"
+            | "dds"
+    )
+}
+
+pub fn is_media_output(ext: &str) -> bool {
+    matches!(
+        canonical_ext(ext),
+        "mp4"
+            | "mkv"
+            | "mov"
+            | "avi"
+            | "mp3"
+            | "wav"
+            | "flac"
+            | "ogg"
+            | "m4a"
+            | "aac"
+            | "webm"
+            | "opus"
+            | "m4v"
+            | "ts"
+            | "mts"
+            | "flv"
+            | "gif"
+            | "mpg"
+            | "vob"
+            | "ogv"
+            | "oga"
+            | "wv"
+            | "ac3"
+            | "dts"
+            | "aiff"
+            | "au"
+            | "amr"
+            | "3gp"
+            | "3g2"
+            | "mka"
+            | "mxf"
+            | "asf"
+            | "wmv"
+            | "rm"
+            | "rmvb"
+            | "adts"
+            | "spx"
+    )
+}
+
+pub fn is_audio_output(ext: &str) -> bool {
+    matches!(
+        canonical_ext(ext),
+        "mp3"
+            | "wav"
+            | "flac"
+            | "ogg"
+            | "m4a"
+            | "aac"
+            | "opus"
+            | "oga"
+            | "wv"
+            | "ac3"
+            | "dts"
+            | "aiff"
+            | "au"
+            | "amr"
+            | "mka"
+            | "adts"
+            | "spx"
+    )
+}
+
+/// Image formats ffmpeg encodes itself when grabbing a video frame. Other
+/// image targets go through PNG and vips.
+pub fn is_frame_output(ext: &str) -> bool {
+    matches!(
+        canonical_ext(ext),
+        "png" | "jpg" | "webp" | "bmp" | "tiff" | "gif" | "ppm" | "pgm" | "pbm" | "tga"
+    )
+}
+
+/// Formats whose usual encoding discards detail, so every conversion from
+/// one re-encodes an already degraded copy.
+pub fn is_lossy(ext: &str) -> bool {
+    matches!(
+        canonical_ext(ext),
+        "jpg"
+            | "webp"
+            | "avif"
+            | "heic"
+            | "jxl"
+            | "jp2"
+            | "j2k"
+            | "jpc"
+            | "jpt"
+            | "j2c"
+            | "gif"
+            | "mp3"
+            | "ogg"
+            | "m4a"
+            | "aac"
+            | "opus"
+            | "oga"
+            | "ac3"
+            | "dts"
+            | "amr"
+            | "adts"
+            | "spx"
+            | "mp4"
+            | "mkv"
+            | "mov"
+            | "avi"
+            | "webm"
+            | "m4v"
+            | "ts"
+            | "mts"
+            | "flv"
+            | "mpg"
+            | "vob"
+            | "ogv"
+            | "3gp"
+            | "3g2"
+            | "mxf"
+            | "asf"
+            | "wmv"
+            | "rm"
+            | "rmvb"
+    )
+}
+
+/// Video containers.
+pub fn is_video_container(ext: &str) -> bool {
+    is_media_output(ext) && !is_audio_output(ext) && canonical_ext(ext) != "gif"
+}
+
+/// Whether the streams usually found in a `from` file are allowed in a `to`
+/// container, so converting can copy them instead of re-encoding, which
+/// loses nothing even when both are lossy formats. WebM and Ogg only take
+/// their own codecs, while Matroska takes nearly anything.
+pub fn can_copy_streams(from: &str, to: &str) -> bool {
+    let (from, to) = (canonical_ext(from), canonical_ext(to));
+    if !is_media_output(from) || !is_media_output(to) || from == "gif" || to == "gif" {
+        return false;
+    }
+    if from == to {
+        return true;
+    }
+    let mpeg4 = |ext: &str| matches!(ext, "mp4" | "m4v" | "mov" | "3gp" | "3g2");
+    let h264 = |ext: &str| mpeg4(ext) || matches!(ext, "mkv" | "ts" | "mts" | "flv");
+    match to {
+        "mkv" => is_video_container(from),
+        "mka" => is_audio_output(from),
+        "mp4" | "m4v" | "mov" | "3gp" | "3g2" | "ts" | "mts" => h264(from),
+        "m4a" | "aac" | "adts" => matches!(from, "m4a" | "aac" | "adts"),
+        "ogg" | "oga" => matches!(from, "ogg" | "oga"),
+        _ => false,
+    }
+}
+
+pub fn has_image_quality(ext: &str) -> bool {
+    matches!(
+        canonical_ext(ext),
+        "jpg" | "webp" | "avif" | "heic" | "jxl" | "jp2"
+    )
+}
+
+pub fn is_doc_output(ext: &str) -> bool {
+    matches!(
+        canonical_ext(ext),
+        "md" | "txt"
+            | "html"
+            | "docx"
+            | "odt"
+            | "epub"
+            | "tex"
+            | "rst"
+            | "rtf"
+            | "org"
+            | "wiki"
+            | "textile"
+            | "fb2"
+            | "ipynb"
+            | "jira"
+            | "opml"
+            | "json"
+            | "typst"
+            | "djot"
+            | "man"
+            | "pdf"
+            | "pptx"
+            | "beamer"
+            | "icml"
+            | "tei"
+            | "texinfo"
+            | "context"
+            | "ms"
+            | "adoc"
+    )
+}
+
+pub fn is_doc_folder_ext(ext: &str) -> bool {
+    matches!(
+        canonical_ext(ext),
+        "md" | "txt"
+            | "html"
+            | "docx"
+            | "odt"
+            | "epub"
+            | "tex"
+            | "rst"
+            | "rtf"
+            | "org"
+            | "textile"
+            | "ipynb"
+            | "typst"
+    )
+}
+
+pub fn pandoc_from_ext(ext: &str) -> &'static str {
+    match canonical_ext(ext) {
+        "html" => "html",
+        "docx" => "docx",
+        "odt" => "odt",
+        "epub" => "epub",
+        "tex" => "latex",
+        "rst" => "rst",
+        "rtf" => "rtf",
+        "org" => "org",
+        "wiki" => "mediawiki",
+        "textile" => "textile",
+        "fb2" => "fb2",
+        "ipynb" => "ipynb",
+        "jira" => "jira",
+        "opml" => "opml",
+        "json" => "json",
+        "typst" => "typst",
+        "djot" => "djot",
+        "csv" => "csv",
+        "tsv" => "tsv",
+        "t2t" => "t2t",
+        "creole" => "creole",
+        "twiki" => "twiki",
+        "man" | "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" => "man",
+        "xml" => "docbook",
+        _ => "markdown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_aliases_to_their_canonical_name() {
+        assert_eq!(canonical_ext("jpeg"), "jpg");
+        assert_eq!(canonical_ext("jfif"), "jpg");
+        assert_eq!(canonical_ext("tif"), "tiff");
+        assert_eq!(canonical_ext("markdown"), "md");
+        assert_eq!(canonical_ext("jpg"), "jpg");
+        assert_eq!(canonical_ext("webp"), "webp");
+    }
+
+    #[test]
+    fn classifies_aliases_like_their_format() {
+        assert!(is_lossy("jpeg"));
+        assert!(has_image_quality("heif"));
+        assert!(is_image_output("tif"));
+        assert!(is_doc_output("htm"));
+        assert_eq!(pandoc_from_ext("latex"), "latex");
+    }
+
+    #[test]
+    fn compares_formats_ignoring_case_and_spelling() {
+        assert!(same_format("JPEG", "jpg"));
+        assert!(same_format("Tif", "TIFF"));
+        assert!(!same_format("jpg", "png"));
+    }
+
+    #[test]
+    fn copies_streams_only_into_containers_that_take_them() {
+        assert!(can_copy_streams("mkv", "mp4"));
+        assert!(can_copy_streams("mp4", "mkv"));
+        assert!(can_copy_streams("mpeg", "mpg"));
+        assert!(can_copy_streams("aac", "m4a"));
+        assert!(!can_copy_streams("mp4", "webm"));
+        assert!(!can_copy_streams("mp3", "ogg"));
+        assert!(!can_copy_streams("gif", "gif"));
+        assert!(!can_copy_streams("png", "mkv"));
+    }
+}
@@ -0,0 +1,556 @@
+use crate::config::{format_size, Config, CONFIG_PATH};
+use crate::history::stored_at;
+use crate::home_dir_for_uid;
+use crate::store::{
+    is_blob_name, is_lineage_id, is_shard_name, list_versions, stored_nanos, Manifest, PinnedDir,
+    BLOBS_DIR, MANIFEST_NAME,
+};
+use anyhow::{anyhow, Context, Result};
+use nix::libc;
+use nix::unistd::{Uid, User};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "usage: morph-bang gc [--dry-run]";
+
+/// Which `[versions]` limit a version is removed for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reason {
+    Count,
+    Age,
+    Size,
+}
+
+impl Reason {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Count => "over max_versions",
+            Self::Age => "older than max_age_days",
+            Self::Size => "over max_total_size",
+        }
+    }
+}
+
+struct Version {
+    path: PathBuf,
+    size: u64,
+    /// Versions with the same contents share a blob, and so an inode.
+    ino: u64,
+    nanos: u128,
+    removal: Option<Reason>,
+}
+
+/// One version directory and its versions, oldest first.
+struct StoreDir {
+    name: String,
+    /// Resolved through the store's [`PinnedDir`].
+    path: PathBuf,
+    versions: Vec<Version>,
+}
+
+/// What the retention limits remove from one user's store.
+struct Plan {
+    root: PathBuf,
+    uid: u32,
+    /// The opened store, or `None` if it could not be opened safely.
+    store: Option<PinnedDir>,
+    dirs: Vec<StoreDir>,
+}
+
+impl Plan {
+    /// Versions removed, bytes freed and bytes kept. Contents still used by
+    /// a kept version are not freed, and shared contents count once.
+    fn totals(&self) -> (usize, u64, u64) {
+        let versions = || self.dirs.iter().flat_map(|d| &d.versions);
+        let kept: HashMap<u64, u64> = versions()
+            .filter(|v| v.removal.is_none())
+            .map(|v| (v.ino, v.size))
+            .collect();
+        let freed: HashMap<u64, u64> = versions()
+            .filter(|v| v.removal.is_some() && !kept.contains_key(&v.ino))
+            .map(|v| (v.ino, v.size))
+            .collect();
+        (
+            versions().filter(|v| v.removal.is_some()).count(),
+            freed.values().sum(),
+            kept.values().sum(),
+        )
+    }
+}
+
+/// Implements `morph-bang gc [--dry-run]`: applies the retention limits to
+/// every user's store when run as root, otherwise to the caller's.
+pub fn run(args: &[String]) -> Result<()> {
+    let dry_run = match args {
+        [] => false,
+        [flag] if flag == "--dry-run" => true,
+        _ => return Err(anyhow!(USAGE)),
+    };
+    let cfg = Config::load(Path::new(CONFIG_PATH))?;
+    if !cfg.versions.has_retention() {
+        println!("No retention limits set in {CONFIG_PATH}; nothing to remove");
+        return Ok(());
+    }
+    let uid = Uid::current();
+    let roots = if uid.is_root() {
+        store_roots(&cfg)
+    } else {
+        let uid = uid.as_raw();
+        vec![(uid, home_dir_for_uid(uid)?.join(&cfg.versions.store_dir))]
+    };
+    for (uid, root) in roots {
+        let plan = plan(&cfg, &root, uid, SystemTime::now());
+        print_plan(&plan, dry_run);
+        if !dry_run {
+            apply(&plan);
+        }
+    }
+    Ok(())
+}
+
+/// The daemon's periodic pass. Only stores that lost versions are logged.
+pub fn collect(cfg: &Config) {
+    for (uid, root) in store_roots(cfg) {
+        let plan = plan(cfg, &root, uid, SystemTime::now());
+        let (removed, bytes, kept) = plan.totals();
+        apply(&plan);
+        if removed == 0 {
+            continue;
+        }
+        eprintln!(
+            "Morph Bang: gc removed {removed} versions ({}) from {}, {} kept",
+            format_size(bytes),
+            root.display(),
+            format_size(kept)
+        );
+    }
+}
+
+/// The store of every account that has one, with the uid it belongs to.
+fn store_roots(cfg: &Config) -> Vec<(u32, PathBuf)> {
+    let mut roots: Vec<(u32, PathBuf)> = accounts()
+        .into_iter()
+        .filter(|user| user.dir.is_absolute())
+        .map(|user| (user.uid.as_raw(), user.dir.join(&cfg.versions.store_dir)))
+        .filter(|(_, root)| root.is_dir())
+        .collect();
+    roots.sort();
+    roots.dedup();
+    roots
+}
+
+/// Every account NSS lists, as `getent passwd` does, so LDAP and other
+/// directory users count alongside those in `/etc/passwd`.
+fn accounts() -> Vec<User> {
+    let mut users = Vec::new();
+    // getpwent walks process-wide state; gc only enumerates accounts from
+    // the daemon's main loop or the single-threaded CLI.
+    unsafe {
+        libc::setpwent();
+        loop {
+            let entry = libc::getpwent();
+            if entry.is_null() {
+                break;
+            }
+            users.push(User::from(&*entry));
+        }
+        libc::endpwent();
+    }
+    users
+}
+
+/// Marks versions for removal: the oldest beyond `max_versions` in each
+/// directory, then those older than `max_age_days` except each directory's
+/// newest, then the oldest across the store until it fits `max_total_size`.
+/// A store that is a symlink or not owned by `uid` is left alone.
+fn plan(cfg: &Config, root: &Path, uid: u32, now: SystemTime) -> Plan {
+    let limits = &cfg.versions;
+    let cutoff = limits.max_age().map(|age| {
+        now.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(age)
+            .as_nanos()
+    });
+
+    let store = PinnedDir::open(root, uid)
+        .map_err(|err| eprintln!("morph-bang: {err:#}"))
+        .ok();
+    let mut dirs = Vec::new();
+    // Only version directories are read, and symlinked ones are skipped.
+    let entries = store
+        .iter()
+        .flat_map(|store| fs::read_dir(store.path()).into_iter().flatten())
+        .filter_map(|e| e.ok());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !is_lineage_id(&name) || !entry.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        let path = entry.path();
+        let mut versions: Vec<Version> = list_versions(&path)
+            .into_iter()
+            .filter_map(|p| {
+                let meta = fs::symlink_metadata(&p).ok()?;
+                Some(Version {
+                    size: meta.len(),
+                    ino: meta.ino(),
+                    nanos: stored_nanos(&p)?,
+                    path: p,
+                    removal: None,
+                })
+            })
+            .collect();
+        if let Some(max) = limits.max_versions {
+            let excess = versions.len().saturating_sub(max);
+            for version in &mut versions[..excess] {
+                version.removal = Some(Reason::Count);
+            }
+        }
+        if let Some(cutoff) = cutoff {
+            let newest = versions.len().saturating_sub(1);
+            for version in &mut versions[..newest] {
+                if version.removal.is_none() && version.nanos < cutoff {
+                    version.removal = Some(Reason::Age);
+                }
+            }
+        }
+        dirs.push(StoreDir {
+            name,
+            path,
+            versions,
+        });
+    }
+    dirs.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if let Some(max_total) = limits.max_total_bytes() {
+        let mut kept: Vec<&mut Version> = dirs
+            .iter_mut()
+            .flat_map(|d| d.versions.iter_mut())
+            .filter(|v| v.removal.is_none())
+            .collect();
+        kept.sort_by_key(|v| v.nanos);
+        // Contents take space until the last version using them is removed.
+        let mut refs: HashMap<u64, usize> = HashMap::new();
+        let mut total = 0;
+        for version in &kept {
+            let count = refs.entry(version.ino).or_default();
+            if *count == 0 {
+                total += version.size;
+            }
+            *count += 1;
+        }
+        for version in kept {
+            if total <= max_total {
+                break;
+            }
+            let count = refs.entry(version.ino).or_default();
+            *count -= 1;
+            if *count == 0 {
+                total -= version.size;
+            }
+            version.removal = Some(Reason::Size);
+        }
+    }
+
+    Plan {
+        root: root.to_path_buf(),
+        uid,
+        store,
+        dirs,
+    }
+}
+
+fn print_plan(plan: &Plan, dry_run: bool) {
+    let verb = if dry_run { "would remove" } else { "remove" };
+    for dir in &plan.dirs {
+        if !dir.versions.iter().any(|v| v.removal.is_some()) {
+            continue;
+        }
+        let manifest = Manifest::load(&dir.path).unwrap_or_default();
+        let label = if manifest.stem.as_os_str().is_empty() {
+            plan.root.join(&dir.name).display().to_string()
+        } else {
+            manifest.stem.display().to_string()
+        };
+        println!("{label}");
+        for version in dir.versions.iter().filter(|v| v.removal.is_some()) {
+            let original = manifest
+                .record(&version.path)
+                .map_or("", |r| r.original_name.as_str());
+            let line = format!(
+                "  {verb}  {}  {:>9}  {:<24}  {original}",
+                stored_at(&version.path),
+                format_size(version.size),
+                version.removal.map_or("", Reason::label)
+            );
+            println!("{}", line.trim_end());
+        }
+    }
+    let (removed, bytes, kept) = plan.totals();
+    let summary = if dry_run { "Would remove" } else { "Removing" };
+    println!(
+        "{summary} {removed} versions ({}) from {}, {} kept",
+        format_size(bytes),
+        plan.root.display(),
+        format_size(kept)
+    );
+}
+
+/// Removes the marked versions and drops them from each manifest. A
+/// directory left without versions is removed along with its manifest, and
+/// a blob no version links to any more is removed last. Everything is
+/// removed through directories opened by [`PinnedDir`], so nothing outside
+/// the store is touched even if its owner swaps a directory for a symlink.
+fn apply(plan: &Plan) {
+    let Some(store) = &plan.store else {
+        return;
+    };
+    for dir in &plan.dirs {
+        if !dir.versions.iter().any(|v| v.removal.is_some()) {
+            continue;
+        }
+        if let Err(err) = prune_dir(store, dir, plan.uid) {
+            eprintln!("morph-bang: {err:#}");
+        }
+    }
+    if let Err(err) = sweep_blobs(store, plan.uid) {
+        eprintln!("morph-bang: {err:#}");
+    }
+}
+
+fn prune_dir(store: &PinnedDir, dir: &StoreDir, uid: u32) -> Result<()> {
+    let pinned = store.open_dir(&dir.name, uid)?;
+    let path = pinned.path();
+    for version in dir.versions.iter().filter(|v| v.removal.is_some()) {
+        let Some(name) = version.path.file_name() else {
+            continue;
+        };
+        if let Err(err) = fs::remove_file(path.join(name)) {
+            eprintln!(
+                "morph-bang: failed to remove {}: {err}",
+                version.path.display()
+            );
+        }
+    }
+    if list_versions(&path).is_empty() {
+        let _ = fs::remove_file(path.join(MANIFEST_NAME));
+        drop(pinned);
+        let _ = fs::remove_dir(store.path().join(&dir.name));
+        return Ok(());
+    }
+    let mut manifest = Manifest::load(&path)?;
+    let before = manifest.versions.len();
+    manifest.versions.retain(|r| path.join(&r.file).is_file());
+    if manifest.versions.len() == before {
+        return Ok(());
+    }
+    let meta =
+        fs::metadata(&path).with_context(|| format!("failed to read {}", dir.path.display()))?;
+    manifest.save(&path, meta.uid(), meta.gid())
+}
+
+/// Removes blobs whose only remaining link is the blob itself. Only
+/// entries named like shards and blobs are looked at.
+fn sweep_blobs(store: &PinnedDir, uid: u32) -> Result<()> {
+    if fs::symlink_metadata(store.path().join(BLOBS_DIR)).is_err() {
+        return Ok(());
+    }
+    let blobs = store.open_dir(BLOBS_DIR, uid)?;
+    let shards = fs::read_dir(blobs.path())
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok());
+    for shard_entry in shards {
+        let shard_name = shard_entry.file_name().to_string_lossy().into_owned();
+        if !is_shard_name(&shard_name) {
+            continue;
+        }
+        let shard = match blobs.open_dir(&shard_name, uid) {
+            Ok(shard) => shard,
+            Err(err) => {
+                eprintln!("morph-bang: {err:#}");
+                continue;
+            }
+        };
+        let entries = fs::read_dir(shard.path())
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok());
+        for entry in entries {
+            if !is_blob_name(&entry.file_name().to_string_lossy()) {
+                continue;
+            }
+            let unused =
+                fs::symlink_metadata(entry.path()).is_ok_and(|m| m.is_file() && m.nlink() == 1);
+            if unused {
+                if let Err(err) = fs::remove_file(entry.path()) {
+                    eprintln!(
+                        "morph-bang: failed to remove blob {}: {err}",
+                        entry.file_name().to_string_lossy()
+                    );
+                }
+            }
+        }
+        drop(shard);
+        let _ = fs::remove_dir(blobs.path().join(&shard_name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::time::Duration;
+
+    const DAY: u64 = 86_400;
+
+    /// Stores `contents` as a version taken `secs` after the epoch, in the
+    /// version directory named by repeating the hex digit `dir`.
+    fn version(root: &Path, dir: &str, secs: u64, contents: &str) -> PathBuf {
+        let dir = root.join(dir.repeat(64));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!(
+            "{:020}-photo.jpg",
+            u128::from(secs) * 1_000_000_000
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn uid() -> u32 {
+        Uid::current().as_raw()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// Removals by version file name, sorted.
+    fn removals(plan: &Plan) -> Vec<(String, Reason)> {
+        let mut removals: Vec<_> = plan
+            .dirs
+            .iter()
+            .flat_map(|d| &d.versions)
+            .filter_map(|v| Some((v.path.file_name()?.to_str()?.to_string(), v.removal?)))
+            .collect();
+        removals.sort_by(|a, b| a.0.cmp(&b.0));
+        removals
+    }
+
+    fn name(version: &Path) -> String {
+        version.file_name().unwrap().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn removes_the_oldest_beyond_max_versions() {
+        let root = TempDir::new("gc-count");
+        let oldest = version(&root, "a", 1, "one");
+        version(&root, "a", 2, "two");
+        version(&root, "a", 3, "three");
+        version(&root, "d", 1, "other");
+        let mut cfg = Config::default();
+        cfg.versions.max_versions = Some(2);
+
+        let plan = plan(&cfg, &root, uid(), at(10));
+        assert_eq!(removals(&plan), [(name(&oldest), Reason::Count)]);
+    }
+
+    #[test]
+    fn removes_old_versions_but_keeps_each_newest() {
+        let root = TempDir::new("gc-age");
+        let first = version(&root, "a", DAY, "one");
+        let second = version(&root, "a", 2 * DAY, "two");
+        version(&root, "a", 9 * DAY, "three");
+        version(&root, "d", DAY, "alone");
+        let mut cfg = Config::default();
+        cfg.versions.max_age_days = Some(1);
+
+        let plan = plan(&cfg, &root, uid(), at(10 * DAY));
+        assert_eq!(
+            removals(&plan),
+            [(name(&first), Reason::Age), (name(&second), Reason::Age)]
+        );
+    }
+
+    #[test]
+    fn counts_shared_contents_once_against_max_total_size() {
+        let root = TempDir::new("gc-size");
+        let first = version(&root, "a", 1, "shared");
+        let linked = version(&root, "d", 2, "");
+        fs::remove_file(&linked).unwrap();
+        fs::hard_link(&first, &linked).unwrap();
+        version(&root, "d", 3, "unique");
+        let mut cfg = Config::default();
+        cfg.versions.max_total_size = Some("10".to_string());
+
+        // Removing the oldest frees nothing while the newer link remains.
+        let plan = plan(&cfg, &root, uid(), at(10));
+        assert_eq!(
+            removals(&plan),
+            [(name(&first), Reason::Size), (name(&linked), Reason::Size)]
+        );
+        assert_eq!(plan.totals(), (2, 6, 6));
+    }
+
+    #[test]
+    fn removes_only_blobs_no_version_links_to() {
+        let root = TempDir::new("gc-blobs");
+        version(&root, "a", 1, "old");
+        let kept = version(&root, "a", 2, "new");
+        let shard = root.join(BLOBS_DIR).join("ab");
+        fs::create_dir_all(&shard).unwrap();
+        let used = shard.join("ab".repeat(32));
+        fs::hard_link(&kept, &used).unwrap();
+        let unused = shard.join(format!("{}.zst", "ac".repeat(32)));
+        fs::write(&unused, "gone").unwrap();
+        let other = shard.join("notes.txt");
+        fs::write(&other, "not a blob").unwrap();
+        let mut cfg = Config::default();
+        cfg.versions.max_versions = Some(1);
+
+        apply(&plan(&cfg, &root, uid(), at(10)));
+        assert!(used.is_file());
+        assert!(!unused.exists());
+        assert!(other.is_file());
+    }
+
+    #[test]
+    fn leaves_a_symlinked_blob_store_alone() {
+        let root = TempDir::new("gc-symlink");
+        let outside = TempDir::new("gc-outside");
+        let shard = outside.join("ab");
+        fs::create_dir(&shard).unwrap();
+        let victim = shard.join("ab".repeat(32));
+        fs::write(&victim, "not the store's").unwrap();
+        std::os::unix::fs::symlink(&*outside, root.join(BLOBS_DIR)).unwrap();
+        let old = version(&root, "a", 1, "old");
+        version(&root, "a", 2, "new");
+        let mut cfg = Config::default();
+        cfg.versions.max_versions = Some(1);
+
+        apply(&plan(&cfg, &root, uid(), at(10)));
+        assert!(!old.exists());
+        assert!(victim.is_file());
+    }
+
+    #[test]
+    fn leaves_a_symlinked_store_alone() {
+        let outside = TempDir::new("gc-target");
+        let old = version(&outside, "a", 1, "old");
+        version(&outside, "a", 2, "new");
+        let root = TempDir::new("gc-link");
+        let link = root.join("versions");
+        std::os::unix::fs::symlink(&*outside, &link).unwrap();
+        let mut cfg = Config::default();
+        cfg.versions.max_versions = Some(1);
+
+        let plan = plan(&cfg, &link, uid(), at(10));
+        assert!(plan.dirs.is_empty());
+        apply(&plan);
+        assert!(old.is_file());
+    }
+}
@@ -0,0 +1,602 @@
+use crate::archive;
+use crate::config::{Config, CONFIG_PATH};
+use crate::store::{
+    find_latest_version_by_ext, hash_file, hash_version, is_compressed, is_folder_version,
+    is_lineage_id, list_versions, open_version, path_version_dir, restore_version_file, store_root,
+    stored_nanos, tag_lineage, version_dir_for_path, version_ext, Manifest, Storage,
+};
+use crate::{
+    canonical_ext, detect_mime, display_name, history_match, parse_trigger, resolve_presets,
+    user_settings, Action, Owner,
+};
+use anyhow::{anyhow, Context, Result};
+use nix::errno::Errno;
+use nix::unistd::{mkdtemp, Gid, Uid};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const USAGE: &str = "usage: morph-bang history list <path>
+       morph-bang history show <path> <n>
+       morph-bang history restore <path> <n> [destination]
+       morph-bang history diff <path> <n> [m]
+       morph-bang history which <path.!ext>
+       morph-bang history orphans
+       morph-bang history attach <path> <old path | id>
+       morph-bang history export <archive>
+       morph-bang history import <archive> [--map <old prefix>=<new prefix>]...
+
+Versions are numbered from 1, the newest.";
+
+/// Implements `morph-bang history ...`.
+pub fn run(args: &[String]) -> Result<()> {
+    let Some((command, rest)) = args.split_first() else {
+        return Err(anyhow!(USAGE));
+    };
+    let cfg = Config::load(Path::new(CONFIG_PATH))?;
+    match (command.as_str(), rest) {
+        ("list", [path]) => list(&History::open(&cfg, path)?),
+        ("show", [path, n]) => show(&History::open(&cfg, path)?, n),
+        ("restore", [path, n]) => restore(&cfg, &History::open(&cfg, path)?, n, None),
+        ("restore", [path, n, dest]) => restore(&cfg, &History::open(&cfg, path)?, n, Some(dest)),
+        ("diff", [path, n]) => diff(&cfg, &History::open(&cfg, path)?, n, None),
+        ("diff", [path, n, m]) => diff(&cfg, &History::open(&cfg, path)?, n, Some(m)),
+        ("which", [path]) => which(&cfg, path),
+        ("orphans", []) => orphans(&cfg),
+        ("attach", [path, old]) => attach(&cfg, path, old),
+        ("export", [dest]) => archive::export(&cfg, dest),
+        ("import", [archive, maps @ ..]) => archive::import(&cfg, archive, maps),
+        _ => Err(anyhow!(USAGE)),
+    }
+}
+
+/// The versions stored for one path, newest first.
+struct History {
+    path: PathBuf,
+    owner: Owner,
+    version_dir: PathBuf,
+    versions: Vec<PathBuf>,
+    manifest: Manifest,
+}
+
+impl History {
+    fn open(cfg: &Config, arg: &str) -> Result<Self> {
+        Self::at(cfg, absolute(Path::new(arg))?)
+    }
+
+    fn at(cfg: &Config, path: PathBuf) -> Result<Self> {
+        let owner = cli_owner(&path)?;
+        let version_dir = version_dir_for_path(cfg, &path, owner.uid)?;
+        let mut versions = list_versions(&version_dir);
+        versions.reverse();
+        let manifest = Manifest::load(&version_dir).unwrap_or_else(|err| {
+            eprintln!("morph-bang: {err:#}");
+            Manifest::default()
+        });
+        Ok(Self {
+            path,
+            owner,
+            version_dir,
+            versions,
+            manifest,
+        })
+    }
+
+    fn get(&self, n: &str) -> Result<&Path> {
+        let index: usize = n
+            .parse()
+            .map_err(|_| anyhow!("{n:?} is not a version number"))?;
+        index
+            .checked_sub(1)
+            .and_then(|i| self.versions.get(i))
+            .map(PathBuf::as_path)
+            .ok_or_else(|| {
+                anyhow!(
+                    "no version {n} for {} ({} stored)",
+                    self.path.display(),
+                    self.versions.len()
+                )
+            })
+    }
+
+    fn number_of(&self, version: &Path) -> usize {
+        self.versions
+            .iter()
+            .position(|v| v == version)
+            .map_or(0, |i| i + 1)
+    }
+}
+
+fn list(history: &History) -> Result<()> {
+    if history.versions.is_empty() {
+        println!("No versions stored for {}", history.path.display());
+        return Ok(());
+    }
+    println!(
+        "{:>3}  {:<19}  {:<6}  {:>12}  {:<12}  {:<7}  ORIGINAL",
+        "#", "STORED (UTC)", "FORMAT", "SIZE", "HASH", "STORAGE"
+    );
+    for (idx, version) in history.versions.iter().enumerate() {
+        let record = history.manifest.record(version);
+        // The recorded size is the content's, before any compression.
+        let size = match record {
+            Some(r) => r.size,
+            None => fs::metadata(version).map(|m| m.len()).unwrap_or(0),
+        };
+        let hash = match record {
+            Some(r) => Some(r.hash.clone()),
+            None => hash_version(version).ok().map(|h| h.to_hex().to_string()),
+        };
+        let storage = hash
+            .as_deref()
+            .and_then(|hash| Storage::of(version, hash).ok())
+            .map_or("?", |s| s.label());
+        let hash = hash
+            .as_deref()
+            .and_then(|hash| hash.get(..12))
+            .unwrap_or("?");
+        println!(
+            "{:>3}  {:<19}  {:<6}  {:>12}  {:<12}  {:<7}  {}",
+            idx + 1,
+            stored_at(version),
+            version_format(version),
+            size,
+            hash,
+            storage,
+            record.map_or("", |r| r.original_name.as_str())
+        );
+    }
+    Ok(())
+}
+
+fn show(history: &History, n: &str) -> Result<()> {
+    let version = history.get(n)?;
+    let meta = fs::metadata(version)?;
+    let hash = hash_version(version)?;
+    println!("Version:  {n} of {}", history.versions.len());
+    println!("Stored:   {}", stored_at(version));
+    println!("Format:   {}", version_format(version));
+    match history.manifest.record(version) {
+        Some(r) if r.size != meta.len() => {
+            println!("Size:     {} bytes ({} stored)", r.size, meta.len())
+        }
+        _ => println!("Size:     {} bytes", meta.len()),
+    }
+    match history.manifest.record(version).and_then(|r| r.mode) {
+        Some(mode) => println!("Mode:     {mode:o}"),
+        None => println!("Mode:     unknown"),
+    }
+    println!("Hash:     {}", hash.to_hex());
+    println!(
+        "Storage:  {}",
+        Storage::of(version, &hash.to_hex())?.describe()
+    );
+    if let Some(record) = history.manifest.record(version) {
+        println!("Original: {}", record.original_name);
+        println!("From:     {}", record.source_path.display());
+        println!("MIME:     {}", record.mime);
+        println!("Trigger:  {}", record.trigger);
+        println!("Targets:  {}", record.targets.join(", "));
+    }
+    println!("File:     {}", version.display());
+    println!("Store:    {}", history.version_dir.display());
+    Ok(())
+}
+
+fn restore(cfg: &Config, history: &History, n: &str, dest: Option<&String>) -> Result<()> {
+    let version = history.get(n)?;
+    let dest = match dest {
+        Some(dest) => absolute(Path::new(dest))?,
+        None if is_folder_version(version) => history.path.with_extension(""),
+        None => history
+            .path
+            .with_extension(canonical_ext(&version_format(version).to_lowercase())),
+    };
+    if dest.exists() {
+        return Err(anyhow!(
+            "{} already exists; pass a different destination",
+            dest.display()
+        ));
+    }
+    restore_version_file(cfg, version, &dest, history.owner, None)?;
+    let _ = tag_lineage(&dest, &history.version_dir);
+    println!("Restored version {n} to {}", dest.display());
+    Ok(())
+}
+
+/// Compares version `n` with version `m`, or with the file itself. Text is
+/// shown as a unified diff; anything else is compared by size and hash.
+fn diff(cfg: &Config, history: &History, n: &str, m: Option<&String>) -> Result<()> {
+    let old = history.get(n)?;
+    let (new, new_label) = match m {
+        Some(m) => (history.get(m)?.to_path_buf(), format!("version {m}")),
+        None if history.path.is_file() => (history.path.clone(), display_name(&history.path)),
+        None if history.path.is_dir() => {
+            return Err(anyhow!(
+                "{} is a folder; give a second version to compare with",
+                history.path.display()
+            ))
+        }
+        None => {
+            return Err(anyhow!(
+                "{} does not exist; give a second version to compare with",
+                history.path.display()
+            ))
+        }
+    };
+    let old_label = format!("version {n}");
+
+    // Compressed versions are compared in copies in a fresh directory only
+    // this process can enter, so nothing else can plant files or links there.
+    let template = std::env::temp_dir().join("morph-bang-diff-XXXXXX");
+    let scratch = mkdtemp(&template).context("failed to create a scratch directory")?;
+    let result = (|| -> Result<()> {
+        let old = unpacked(old, &scratch, "old")?;
+        let new = match m {
+            Some(_) => unpacked(&new, &scratch, "new")?,
+            None => new,
+        };
+        compare(cfg, &old, &new, &old_label, &new_label)
+    })();
+    let _ = fs::remove_dir_all(&scratch);
+    result
+}
+
+fn compare(cfg: &Config, old: &Path, new: &Path, old_label: &str, new_label: &str) -> Result<()> {
+    let is_text = |p: &Path| detect_mime(cfg, p).is_ok_and(|m| m.starts_with("text/"));
+    if is_text(old) && is_text(new) {
+        let status = Command::new("diff")
+            .arg("-u")
+            .arg("--label")
+            .arg(old_label)
+            .arg("--label")
+            .arg(new_label)
+            .arg(old)
+            .arg(new)
+            .status()
+            .context("failed to run diff")?;
+        if status.code() == Some(0) {
+            println!("{old_label} and {new_label} are identical");
+        }
+        return Ok(());
+    }
+
+    let (old_hash, new_hash) = (hash_file(old)?, hash_file(new)?);
+    let (old_size, new_size) = (fs::metadata(old)?.len(), fs::metadata(new)?.len());
+    if old_hash == new_hash {
+        println!("{old_label} and {new_label} are identical");
+    } else {
+        println!(
+            "{old_label}: {} {old_size} bytes {}",
+            version_format(old),
+            &old_hash.to_hex()[..12]
+        );
+        println!(
+            "{new_label}: {} {new_size} bytes {}",
+            version_format(new),
+            &new_hash.to_hex()[..12]
+        );
+    }
+    Ok(())
+}
+
+/// `version` itself, or a decompressed copy of it named `<name>.<ext>` in
+/// `scratch`.
+fn unpacked(version: &Path, scratch: &Path, name: &str) -> Result<PathBuf> {
+    if !is_compressed(version) {
+        return Ok(version.to_path_buf());
+    }
+    let copy = scratch.join(format!("{name}.{}", version_ext(version).unwrap_or("bin")));
+    let mut out = fs::File::create_new(&copy)?;
+    std::io::copy(&mut open_version(version)?, &mut out)
+        .with_context(|| format!("failed to decompress {}", version.display()))?;
+    Ok(copy)
+}
+
+/// Reports, per target of a trigger name, whether the daemon would restore
+/// a version from history or convert. The trigger acts on the named file if
+/// it exists, otherwise on the newest file with the same stem.
+fn which(cfg: &Config, arg: &str) -> Result<()> {
+    let trigger_path = absolute(Path::new(arg))?;
+    let raw_ext = trigger_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("");
+    let Some(trigger) = parse_trigger(raw_ext)? else {
+        return Err(anyhow!(
+            "{} is not a trigger name such as photo.!jpg",
+            trigger_path.display()
+        ));
+    };
+    if trigger.action != Action::Convert {
+        return Err(anyhow!("which only applies to conversion triggers"));
+    }
+    let source = trigger_source(&trigger_path).ok_or_else(|| {
+        anyhow!(
+            "no file named {} to trigger",
+            trigger_path.with_extension("*").display()
+        )
+    })?;
+    let history = History::at(cfg, source.clone())?;
+    let settings = user_settings(cfg, history.owner.uid)?;
+    let trigger = resolve_presets(trigger, &settings)?;
+    let current_hash = hash_file(&source)?.to_hex().to_string();
+    println!("Source: {}", display_name(&source));
+    for target in &trigger.targets {
+        let label = target.ext.to_uppercase();
+        let stale = find_latest_version_by_ext(&history.version_dir, &target.ext);
+        match history_match(&history.version_dir, target, &current_hash) {
+            Some(version) => println!(
+                "{label}: restores version {} ({}, {})",
+                history.number_of(&version),
+                stored_at(&version),
+                display_name(&version)
+            ),
+            None if !target.options.is_empty() => {
+                println!("{label}: converts, options always bypass history")
+            }
+            None => match stale {
+                Some(version) => println!(
+                    "{label}: converts, {} was not converted to or from version {}",
+                    display_name(&source),
+                    history.number_of(&version)
+                ),
+                None => println!("{label}: converts, no {label} version stored"),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Lists version directories whose file is gone from its last known path,
+/// e.g. after a move that lost the lineage attribute.
+fn orphans(cfg: &Config) -> Result<()> {
+    let root = store_root(cfg, Uid::current().as_raw())?;
+    let mut found = 0;
+    let mut entries: Vec<PathBuf> = fs::read_dir(&root)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(is_lineage_id)
+        })
+        .collect();
+    entries.sort();
+    for dir in entries {
+        let versions = list_versions(&dir);
+        let Some(newest) = versions.last() else {
+            continue;
+        };
+        let manifest = Manifest::load(&dir).unwrap_or_default();
+        let stem = &manifest.stem;
+        if !stem.as_os_str().is_empty() && has_stem_file(stem) {
+            continue;
+        }
+        if found == 0 {
+            println!(
+                "{:<64}  {:>8}  {:<19}  LAST PATH",
+                "ID", "VERSIONS", "NEWEST (UTC)"
+            );
+        }
+        found += 1;
+        println!(
+            "{}  {:>8}  {:<19}  {}",
+            display_name(&dir),
+            versions.len(),
+            stored_at(newest),
+            if stem.as_os_str().is_empty() {
+                "unknown".to_string()
+            } else {
+                format!("{}.*", stem.display())
+            }
+        );
+    }
+    if found == 0 {
+        println!("No orphaned history in {}", root.display());
+    }
+    Ok(())
+}
+
+/// Whether any file named `<stem>.<ext>` exists.
+fn has_stem_file(stem: &Path) -> bool {
+    let (Some(parent), Some(name)) = (stem.parent(), stem.file_name()) else {
+        return false;
+    };
+    fs::read_dir(parent)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .any(|e| Path::new(&e.file_name()).file_stem() == Some(name))
+}
+
+/// Makes the history of `old`, a former path or a version directory id,
+/// the history of `path`. The file is tagged with the id; on a filesystem
+/// without user xattrs the directory is re-keyed to the new path instead.
+fn attach(cfg: &Config, path: &str, old: &str) -> Result<()> {
+    let path = absolute(Path::new(path))?;
+    if !path.is_file() && !path.is_dir() {
+        return Err(anyhow!("{} is not a file or folder", path.display()));
+    }
+    let owner = cli_owner(&path)?;
+    let root = store_root(cfg, owner.uid)?;
+    let old_dir = if is_lineage_id(old) {
+        root.join(old)
+    } else {
+        version_dir_for_path(cfg, &absolute(Path::new(old))?, owner.uid)?
+    };
+    let count = list_versions(&old_dir).len();
+    if count == 0 {
+        return Err(anyhow!("no history stored for {old}"));
+    }
+
+    let dir = match tag_lineage(&path, &old_dir) {
+        Ok(()) => old_dir,
+        Err(err) if err.raw_os_error() == Some(Errno::ENOTSUP as i32) => {
+            let new_dir = path_version_dir(cfg, &path, owner.uid)?;
+            if new_dir != old_dir {
+                if !list_versions(&new_dir).is_empty() {
+                    return Err(anyhow!(
+                        "{} already has history and its filesystem cannot record another",
+                        path.display()
+                    ));
+                }
+                let _ = fs::remove_dir_all(&new_dir);
+                fs::rename(&old_dir, &new_dir)
+                    .with_context(|| format!("failed to move {}", old_dir.display()))?;
+            }
+            new_dir
+        }
+        Err(err) => return Err(err).with_context(|| format!("failed to tag {}", path.display())),
+    };
+
+    let mut manifest = Manifest::load(&dir)?;
+    manifest.stem = path.with_extension("");
+    let meta = fs::metadata(&dir)?;
+    manifest.save(&dir, meta.uid(), meta.gid())?;
+    println!(
+        "Attached {count} versions to {} (history {})",
+        path.display(),
+        display_name(&dir)
+    );
+    Ok(())
+}
+
+fn trigger_source(trigger_path: &Path) -> Option<PathBuf> {
+    if trigger_path.is_file() {
+        return Some(trigger_path.to_path_buf());
+    }
+    let stem = trigger_path.file_stem()?;
+    fs::read_dir(trigger_path.parent()?)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_stem() == Some(stem)
+                && p.extension()
+                    .is_some_and(|e| !e.as_bytes().starts_with(b"!"))
+                && p.is_file()
+        })
+        .max_by_key(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+}
+
+/// The user whose history a command reads: the caller, or for root the
+/// owner the daemon would pick for the path.
+fn cli_owner(path: &Path) -> Result<Owner> {
+    let uid = Uid::current();
+    if !uid.is_root() {
+        return Ok(Owner {
+            uid: uid.as_raw(),
+            gid: Gid::current().as_raw(),
+            mode: 0o644,
+        });
+    }
+    let probe = if path.exists() {
+        path
+    } else {
+        path.parent().unwrap_or(Path::new("/"))
+    };
+    Owner::from_path(probe)
+}
+
+/// Makes `path` absolute the way the daemon sees it, without requiring the
+/// file itself to exist.
+fn absolute(path: &Path) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path", path.display()))?;
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let parent = fs::canonicalize(parent)
+        .with_context(|| format!("failed to resolve {}", parent.display()))?;
+    Ok(parent.join(name))
+}
+
+fn version_format(version: &Path) -> String {
+    version_ext(version).unwrap_or_default().to_uppercase()
+}
+
+/// The store time encoded in a version name, as `YYYY-MM-DD HH:MM:SS`.
+pub fn stored_at(version: &Path) -> String {
+    match stored_nanos(version) {
+        Some(nanos) => format_utc((nanos / 1_000_000_000) as i64),
+        None => "unknown".to_string(),
+    }
+}
+
+fn format_utc(secs: i64) -> String {
+    let days = secs.div_euclid(86_400);
+    let rem = secs.rem_euclid(86_400);
+    // Civil-from-days, after Howard Hinnant's date algorithms.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{lineage_id, path_version_dir};
+    use crate::test_util::{config_with_store, supports_lineage, TempDir};
+
+    /// A history directory in `store` holding one version.
+    fn history_with_a_version(dir: &Path) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("00000000000000000001-00001-0000.png"), "pixels").unwrap();
+    }
+
+    #[test]
+    fn attaches_history_by_its_id() {
+        let root = TempDir::new("history-attach-id");
+        let cfg = config_with_store(&root.join("store"));
+        let id = "c".repeat(64);
+        history_with_a_version(&root.join("store").join(&id));
+        let photo = root.join("photo.png");
+        fs::write(&photo, "pixels").unwrap();
+        if !supports_lineage(&photo) {
+            return;
+        }
+
+        attach(&cfg, photo.to_str().unwrap(), &id).unwrap();
+        assert_eq!(lineage_id(&photo), Some(id.clone()));
+        let manifest = Manifest::load(&root.join("store").join(&id)).unwrap();
+        assert_eq!(manifest.stem, absolute(&photo).unwrap().with_extension(""));
+    }
+
+    #[test]
+    fn attaches_history_by_a_former_path() {
+        let root = TempDir::new("history-attach-path");
+        let cfg = config_with_store(&root.join("store"));
+        let uid = cli_owner(&root).unwrap().uid;
+        let old = absolute(&root.join("old.png")).unwrap();
+        let old_dir = path_version_dir(&cfg, &old, uid).unwrap();
+        history_with_a_version(&old_dir);
+        let photo = root.join("new.png");
+        fs::write(&photo, "pixels").unwrap();
+        if !supports_lineage(&photo) {
+            return;
+        }
+
+        attach(&cfg, photo.to_str().unwrap(), old.to_str().unwrap()).unwrap();
+        assert_eq!(lineage_id(&photo), Some(display_name(&old_dir)));
+        assert!(attach(&cfg, photo.to_str().unwrap(), "/nowhere/else.png").is_err());
+    }
+}
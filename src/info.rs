@@ -0,0 +1,158 @@
+use crate::config::{format_size, Config};
+use crate::engine::pdf_pages;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Command;
+
+/// What `.!info` writes to `<name>.info.json`. Sections are only present
+/// when the matching tool knows the format.
+#[derive(Debug, Serialize)]
+pub struct FileInfo {
+    pub file: String,
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch.
+    pub modified: i64,
+    pub mime: String,
+    pub extension: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pdf_pages: Option<u32>,
+    /// `vipsheader -a` fields, e.g. `width`, `height`, `bands`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<BTreeMap<String, String>>,
+    /// `ffprobe` format and stream report.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media: Option<serde_json::Value>,
+}
+
+/// Collects what the configured tools report about `path`. A tool that is
+/// missing or fails only leaves its section out.
+pub fn gather(cfg: &Config, path: &Path, mime: &str, ext: &str) -> std::io::Result<FileInfo> {
+    let meta = fs::metadata(path)?;
+    let image_like =
+        mime.starts_with("image/") || mime == "application/pdf" || mime == "application/postscript";
+    let media_like = mime.starts_with("video/") || mime.starts_with("audio/");
+    Ok(FileInfo {
+        file: path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size: meta.len(),
+        modified: meta.mtime(),
+        mime: mime.to_string(),
+        extension: ext.to_string(),
+        encoding: mime
+            .starts_with("text/")
+            .then(|| text_encoding(cfg, path))
+            .flatten(),
+        pdf_pages: (mime == "application/pdf")
+            .then(|| pdf_pages(cfg, path))
+            .flatten(),
+        image: image_like.then(|| vips_header(cfg, path)).flatten(),
+        media: media_like.then(|| ffprobe(cfg, path)).flatten(),
+    })
+}
+
+impl FileInfo {
+    /// One line for the notification, e.g.
+    /// `clip.mp4: video/mp4, 48.2 MiB, h264 1920x1080, aac, 3m12s`.
+    pub fn summary(&self) -> String {
+        let mut parts = vec![self.mime.clone(), format_size(self.size)];
+        if let Some(encoding) = &self.encoding {
+            parts.push(encoding.clone());
+        }
+        if let Some(pages) = self.pdf_pages {
+            parts.push(format!("{pages} pages"));
+        }
+        if let Some(image) = &self.image {
+            if let (Some(w), Some(h)) = (image.get("width"), image.get("height")) {
+                parts.push(format!("{w}x{h}"));
+            }
+        }
+        if let Some(media) = &self.media {
+            parts.extend(media_summary(media));
+        }
+        format!("{}: {}", self.file, parts.join(", "))
+    }
+}
+
+fn text_encoding(cfg: &Config, path: &Path) -> Option<String> {
+    let out = Command::new(&cfg.engines.file)
+        .arg("--mime-encoding")
+        .arg("-b")
+        .arg(path)
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+fn vips_header(cfg: &Config, path: &Path) -> Option<BTreeMap<String, String>> {
+    let out = Command::new(&cfg.engines.vipsheader)
+        .arg("-a")
+        .arg(path)
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let fields: BTreeMap<String, String> = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+    (!fields.is_empty()).then_some(fields)
+}
+
+fn ffprobe(cfg: &Config, path: &Path) -> Option<serde_json::Value> {
+    let out = Command::new(&cfg.engines.ffprobe)
+        .arg("-v")
+        .arg("error")
+        .arg("-print_format")
+        .arg("json")
+        .arg("-show_format")
+        .arg("-show_streams")
+        .arg(path)
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    serde_json::from_slice(&out.stdout).ok()
+}
+
+/// Codec and size of each stream, then the duration.
+fn media_summary(media: &serde_json::Value) -> Vec<String> {
+    let mut parts = Vec::new();
+    let streams = media["streams"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or(&[]);
+    for stream in streams {
+        let Some(codec) = stream["codec_name"].as_str() else {
+            continue;
+        };
+        match (stream["width"].as_u64(), stream["height"].as_u64()) {
+            (Some(w), Some(h)) => parts.push(format!("{codec} {w}x{h}")),
+            _ => parts.push(codec.to_string()),
+        }
+    }
+    let duration = media["format"]["duration"]
+        .as_str()
+        .and_then(|d| d.parse::<f64>().ok());
+    if let Some(secs) = duration {
+        let secs = secs.round() as u64;
+        parts.push(match secs {
+            0..=59 => format!("{secs}s"),
+            60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+            _ => format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+        });
+    }
+    parts
+}
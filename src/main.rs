@@ -1,24 +1,189 @@
+mod archive;
+mod config;
+mod engine;
+mod formats;
+mod gc;
+mod history;
+mod info;
+mod options;
+mod pipeline;
+mod reflink;
+mod store;
+#[cfg(test)]
+mod test_util;
+mod watcher;
+
 use anyhow::{anyhow, Context, Result};
-use nix::unistd::{chown, Gid, Uid, User};
+use config::{
+    Config, LossyPolicy, Settings, UserConfig, Verbosity, CONFIG_PATH, RESERVED_TARGETS,
+    USER_CONFIG_PATH,
+};
+use engine::{
+    check_options, copies_streams, copy_owner_and_perms, create_workspace, morph_engine, pdf_pages,
+    run_cmd, Engine,
+};
+use formats::{
+    canonical_ext, is_audio_output, is_doc_folder_ext, is_image_output, is_lossy,
+    is_video_container, pandoc_from_ext, same_format,
+};
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::stat::Mode;
+use nix::unistd::{chown, Gid, Pid, Uid, User};
+use options::ConvertOptions;
+use pipeline::Step;
 use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs;
-use std::io::{BufRead, BufReader};
-use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::os::unix::io::AsFd;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
 use std::time::{Duration, Instant};
+use store::{
+    adopt_legacy_versions, ancestors, ensure_version_paths_owned, find_lineage_version, hash_file,
+    hash_path, hash_version, is_folder_version, latest_version_where, record_conversion,
+    replace_file_at, restore_version_file, store_version, tag_lineage, version_dir_for_path,
+    version_ext, Manifest, Origin, FOLDER_EXT, FOLDER_MIME,
+};
 use walkdir::WalkDir;
+use watcher::{is_trigger_name, Watcher};
 
-const WATCH_DIR: &str = "/home";
-const LOCK_TTL: Duration = Duration::from_secs(2);
-const PDF_OUTPUT_PAGE_SIZE: &str = "letter";
-const PDF_OUTPUT_PROFILE: &str = "/ebook";
+const PID_FILE: &str = "/run/morph-bang.pid";
+/// Consecutive failed inotify reads after which the daemon exits.
+const MAX_READ_ERRORS: u32 = 10;
+/// Pages of a PDF go to a folder named after it, which two targets would
+/// both write to.
+const SPLIT_TARGETS_ERROR: &str = "only one target can split a multi-page PDF into pages";
 
 #[derive(Debug, Clone)]
 struct Trigger {
-    target_ext: String,
+    /// The extension as typed, e.g. `!webp@q80+avif`.
+    raw: String,
+    action: Action,
+    targets: Vec<Target>,
     destructive: bool,
+    /// `.!?<ext>`: report what would happen instead of converting.
+    preview: bool,
+}
+
+/// What a trigger asks for. Built-in actions use a reserved name in place of
+/// a target extension, e.g. `.!info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Convert,
+    Info,
+    Undo,
+}
+
+#[derive(Debug, Clone)]
+struct Target {
+    ext: String,
+    options: ConvertOptions,
+}
+
+impl Trigger {
+    /// How a version stored for this trigger is described in the manifest.
+    /// `name` is what the file was called before it was renamed to trigger.
+    fn origin<'a>(&'a self, source: &'a Source, name: &'a str) -> Origin<'a> {
+        Origin {
+            name,
+            mime: &source.mime,
+            ext: &source.ext,
+            trigger: &self.raw,
+            targets: self.targets.iter().map(Target::to_string).collect(),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.options.is_empty() {
+            f.write_str(&self.ext)
+        } else {
+            write!(f, "{}@{}", self.ext, self.options)
+        }
+    }
+}
+
+/// What `file` reports about the triggered file.
+struct Source {
+    mime: String,
+    ext: String,
+}
+
+impl Source {
+    /// What `path` holds, by its contents rather than its name.
+    fn detect(cfg: &Config, path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Ok(folder_source());
+        }
+        Ok(Self {
+            mime: detect_mime(cfg, path)?,
+            ext: detect_source_ext(cfg, path),
+        })
+    }
+}
+
+/// A stored ancestor of the triggered file that conversions read instead of
+/// the file itself.
+struct Original {
+    version: PathBuf,
+    hash: String,
+    source: Source,
+    name: String,
+    /// The pages and size the file was converted from it with, applied
+    /// again to every conversion from it.
+    options: ConvertOptions,
+}
+
+/// How one target is produced.
+struct TargetPlan<'a> {
+    steps: Vec<Step>,
+    /// A version restored instead of converting.
+    history: Option<PathBuf>,
+    /// Converting from a stored ancestor rather than the file.
+    original: Option<&'a Original>,
+    /// Set when the conversion re-encodes lossy data.
+    warning: Option<String>,
+}
+
+impl TargetPlan<'_> {
+    /// The options `target` is converted with: its own, over those replayed
+    /// from the original it is converted from.
+    fn options(&self, target: &Target) -> ConvertOptions {
+        match self.original {
+            Some(original) => original.options.clone().overridden_by(&target.options),
+            None => target.options.clone(),
+        }
+    }
+}
+
+/// A target with its plan, `None` when the source cannot be converted to it.
+type PlannedTarget<'a, 't> = (&'t Target, Result<Option<TargetPlan<'a>>>);
+
+/// What a single target conversion left on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Produced {
+    File,
+    Restored,
+    /// A multi-page PDF was expanded into a folder of page images.
+    Folder,
+}
+
+/// The triggered path plus the settings it is handled under.
+struct Job<'a> {
+    cfg: &'a Config,
+    settings: Settings,
+    path: &'a Path,
+    filename: &'a str,
+    /// The path it was renamed from to trigger, when the watcher saw it.
+    origin: Option<&'a Path>,
+    owner: Owner,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -69,177 +234,1258 @@ impl Owner {
 }
 
 fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => run_daemon(),
+        Some("reload") => request_reload(),
+        Some("history") => history::run(&args[1..]),
+        Some("gc") => gc::run(&args[1..]),
+        Some(other) => Err(anyhow!(
+            "unknown command {other:?}; expected: reload, history, gc"
+        )),
+    }
+}
+
+fn run_daemon() -> Result<()> {
+    let mut cfg = Config::load(Path::new(CONFIG_PATH))?;
+    let mut watcher = Watcher::new(&cfg.watch.roots, &cfg.watch.exclude)?;
     eprintln!(
-        "Morph Bang: Global filesystem watch established on {}",
-        WATCH_DIR
+        "Morph Bang: Global filesystem watch established on {} ({} directories)",
+        display_paths(&cfg.watch.roots),
+        watcher.watch_count()
     );
 
-    let mut child = Command::new("inotifywait")
-        .arg("-q")
-        .arg("-m")
-        .arg("-r")
-        .arg("-e")
-        .arg("moved_to")
-        .arg("--format")
-        .arg("%w%f")
-        .arg("--exclude")
-        .arg("/\\..*")
-        .arg(WATCH_DIR)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()
-        .context("failed to start inotifywait")?;
-    let stdout = child
-        .stdout
-        .take()
-        .ok_or_else(|| anyhow!("failed to capture inotifywait stdout"))?;
-    let reader = BufReader::new(stdout);
+    let signals = signal_fd()?;
+    let pid = format!("{}\n", std::process::id());
+    if let Err(err) = fs::write(PID_FILE, &pid) {
+        eprintln!("Morph Bang: failed to write {PID_FILE}: {err}");
+    }
+    let result = serve(&mut cfg, &mut watcher, &signals);
+    // Another daemon may have taken over the file since.
+    if fs::read_to_string(PID_FILE).is_ok_and(|current| current == pid) {
+        let _ = fs::remove_file(PID_FILE);
+    }
+    result
+}
 
+/// Handles triggers until SIGTERM or SIGINT arrives.
+fn serve(cfg: &mut Config, watcher: &mut Watcher, signals: &SignalFd) -> Result<()> {
     let mut locks: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut next_gc = Instant::now();
+    let mut read_errors = 0;
+
+    loop {
+        // Retention runs between jobs too, so it never removes a version a
+        // conversion is about to restore.
+        if cfg.versions.has_retention() && Instant::now() >= next_gc {
+            gc::collect(cfg);
+            next_gc = Instant::now() + cfg.versions.gc_interval();
+        }
+        let timeout = if cfg.versions.has_retention() {
+            PollTimeout::try_from(next_gc.saturating_duration_since(Instant::now()))
+                .unwrap_or(PollTimeout::MAX)
+        } else {
+            PollTimeout::NONE
+        };
 
-    for line in reader.lines() {
-        prune_locks(&mut locks);
-        let line = match line {
-            Ok(v) => v,
-            Err(err) => {
-                eprintln!("inotify read error: {err}");
-                continue;
+        // Jobs run to completion on this thread, so a reload only takes effect
+        // between triggers and never changes settings under a running conversion.
+        let (events_ready, signalled) = {
+            let mut fds = [
+                PollFd::new(watcher.as_fd(), PollFlags::POLLIN),
+                PollFd::new(signals.as_fd(), PollFlags::POLLIN),
+            ];
+            match poll(&mut fds, timeout) {
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(err) => return Err(err).context("failed to wait for events"),
             }
+            let ready = |fd: &PollFd| fd.revents().is_some_and(|r| !r.is_empty());
+            (ready(&fds[0]), ready(&fds[1]))
         };
-        if line.trim().is_empty() {
+
+        if signalled {
+            match signals.read_signal() {
+                Ok(Some(info)) if info.ssi_signo == Signal::SIGHUP as u32 => {
+                    reload_config(cfg, watcher)
+                }
+                Ok(Some(_)) => {
+                    eprintln!("Morph Bang: stopping");
+                    return Ok(());
+                }
+                Ok(None) | Err(_) => {}
+            }
+        }
+        if !events_ready {
             continue;
         }
-        let path = PathBuf::from(line.trim());
-        if let Err(err) = handle_path(&path, &mut locks) {
-            eprintln!("morph-bang error for {}: {err}", path.display());
+
+        let paths = match watcher.next_batch() {
+            Ok(v) => {
+                read_errors = 0;
+                v
+            }
+            Err(err) => {
+                eprintln!("inotify read error: {err:#}");
+                read_errors += 1;
+                if read_errors >= MAX_READ_ERRORS {
+                    return Err(err).context("inotify keeps failing, giving up");
+                }
+                // Reading again right away would most likely fail the same way.
+                std::thread::sleep(Duration::from_millis(100 << read_errors.min(5)));
+                continue;
+            }
         };
+        for moved in paths {
+            prune_locks(&mut locks, cfg.watch.lock_ttl());
+            if let Err(err) = handle_path(cfg, &moved.path, moved.from.as_deref(), &mut locks) {
+                eprintln!("morph-bang error for {}: {err}", moved.path.display());
+            };
+        }
     }
+}
+
+/// SIGHUP reloads the config; SIGTERM and SIGINT stop the daemon between
+/// jobs, so it can clean up after itself.
+fn signal_fd() -> Result<SignalFd> {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGHUP);
+    mask.add(Signal::SIGTERM);
+    mask.add(Signal::SIGINT);
+    mask.thread_block().context("failed to block signals")?;
+    SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC | SfdFlags::SFD_NONBLOCK)
+        .context("failed to create signalfd")
+}
 
+fn reload_config(cfg: &mut Config, watcher: &mut Watcher) {
+    let mut new_cfg = match Config::load(Path::new(CONFIG_PATH)) {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("Morph Bang: reload failed, keeping current settings: {err:#}");
+            return;
+        }
+    };
+    let changes = cfg.describe_changes(&new_cfg);
+    if changes.is_empty() {
+        eprintln!("Morph Bang: reloaded {CONFIG_PATH}, no changes");
+        return;
+    }
+    for change in &changes {
+        eprintln!("Morph Bang: config {change}");
+    }
+    // Histories would silently start over in the new location, while the
+    // old one kept growing with nothing to collect it.
+    if new_cfg.versions.store_dir != cfg.versions.store_dir {
+        eprintln!(
+            "Morph Bang: ignoring the new versions.store_dir {}, still using {}; move the store and restart the daemon to change it",
+            new_cfg.versions.store_dir.display(),
+            cfg.versions.store_dir.display()
+        );
+        new_cfg.versions.store_dir = cfg.versions.store_dir.clone();
+    }
+    if new_cfg.watch.roots != cfg.watch.roots || new_cfg.watch.exclude != cfg.watch.exclude {
+        watcher.reconfigure(&new_cfg.watch.roots, &new_cfg.watch.exclude);
+        eprintln!(
+            "Morph Bang: now watching {} ({} directories)",
+            display_paths(&new_cfg.watch.roots),
+            watcher.watch_count()
+        );
+    }
+    *cfg = new_cfg;
+}
+
+/// Implements `morph-bang reload`: signals the running daemon to re-read its config.
+fn request_reload() -> Result<()> {
+    let raw = fs::read_to_string(PID_FILE)
+        .with_context(|| format!("failed to read {PID_FILE}; is the daemon running?"))?;
+    let pid: i32 = raw
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid pid in {PID_FILE}"))?;
+    let comm = fs::read_to_string(format!("/proc/{pid}/comm")).unwrap_or_default();
+    if comm.trim() != "morph-bang" {
+        return Err(anyhow!("no morph-bang daemon running as pid {pid}"));
+    }
+    kill(Pid::from_raw(pid), Signal::SIGHUP)
+        .with_context(|| format!("failed to signal pid {pid}"))?;
+    eprintln!("Morph Bang: reload requested (pid {pid})");
     Ok(())
 }
 
-fn handle_path(path: &Path, locks: &mut HashMap<PathBuf, Instant>) -> Result<()> {
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn handle_path(
+    cfg: &Config,
+    path: &Path,
+    origin: Option<&Path>,
+    locks: &mut HashMap<PathBuf, Instant>,
+) -> Result<()> {
     let filename = path
         .file_name()
         .and_then(|n| n.to_str())
         .ok_or_else(|| anyhow!("invalid filename"))?;
     let raw_ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
-    let Some(trigger) = parse_trigger(raw_ext) else {
+    let parsed = parse_trigger(raw_ext);
+    if matches!(parsed, Ok(None)) || !path.exists() {
         return Ok(());
+    }
+
+    let owner = Owner::from_path(path)?;
+    let job = Job {
+        cfg,
+        settings: settings_for_uid(cfg, owner.uid),
+        path,
+        filename,
+        origin,
+        owner,
     };
-    let clean_path = path.with_extension(&trigger.target_ext);
 
-    if is_locked(locks, &clean_path) {
+    let result = parsed.and_then(|trigger| match trigger {
+        Some(trigger) => resolve_presets(trigger, &job.settings)
+            .and_then(|trigger| handle_trigger(&job, trigger, locks)),
+        None => Ok(()),
+    });
+    if let Err(err) = &result {
+        // A trigger that failed would otherwise fire again on every rescan.
+        if path.exists() {
+            if let Ok(source) = Source::detect(cfg, path) {
+                restore_source_name(&job, &source);
+            }
+        }
+        notify_error(
+            &job.settings,
+            owner.uid,
+            &format!("Could not morph {filename}: {err}"),
+        );
+    }
+    result
+}
+
+fn handle_trigger(
+    job: &Job,
+    trigger: Trigger,
+    locks: &mut HashMap<PathBuf, Instant>,
+) -> Result<()> {
+    let path = job.path;
+    let clean_paths: Vec<PathBuf> = trigger
+        .targets
+        .iter()
+        .map(|t| path.with_extension(&t.ext))
+        .collect();
+    let ttl = job.cfg.watch.lock_ttl();
+    if clean_paths.iter().any(|p| is_locked(locks, p, ttl)) {
         return Ok(());
     }
-    lock(locks, clean_path.clone());
+    for clean_path in &clean_paths {
+        lock(locks, clean_path.clone());
+    }
 
-    if !path.exists() {
+    if trigger.action == Action::Info {
+        return handle_info(job);
+    }
+    if !path.is_file() && !path.is_dir() {
         return Ok(());
     }
 
-    let owner = Owner::from_path(path)?;
+    // A preview only reads history, so it neither creates nor migrates it.
+    let source = Source::detect(job.cfg, path)?;
+    let version_dir = version_dir_for_path(job.cfg, path, job.owner.uid)?;
+    if trigger.preview {
+        return if path.is_dir() {
+            handle_directory_preview(job, &trigger, &version_dir)
+        } else {
+            handle_preview(job, &trigger, &source, &version_dir)
+        };
+    }
+    ensure_version_paths_owned(&version_dir, job.owner.uid, job.owner.gid)?;
+    tag_history(path, &version_dir);
+    for clean_path in &clean_paths {
+        adopt_legacy_versions(job.cfg, clean_path, &version_dir, job.owner.uid);
+    }
+    if trigger.action == Action::Undo {
+        return handle_undo(job, &trigger, &source, &version_dir);
+    }
+    if path.is_dir() {
+        return handle_directory_trigger(job, &trigger, &version_dir);
+    }
+    handle_file_trigger(job, &trigger, &source, &version_dir)
+}
+
+/// Expands each `.!<preset>` target into the preset's extension and options.
+/// Options given on the trigger itself win over the preset's. Fails when two
+/// targets end up in the same format, as `parse_trigger` does before
+/// expansion, since the second output would replace the first.
+fn resolve_presets(mut trigger: Trigger, settings: &Settings) -> Result<Trigger> {
+    for target in &mut trigger.targets {
+        if let Some(preset) = settings.presets.get(&target.ext) {
+            let options = preset
+                .options()
+                .with_context(|| format!("preset {:?} has invalid options", target.ext))?;
+            target.ext = preset.target.clone();
+            target.options = options.overridden_by(&target.options);
+        }
+    }
+    for (idx, target) in trigger.targets.iter().enumerate() {
+        if trigger.targets[..idx]
+            .iter()
+            .any(|t| same_format(&t.ext, &target.ext))
+        {
+            return Err(anyhow!(
+                "{:?}: target {} is produced twice",
+                trigger.raw,
+                target.ext.to_uppercase()
+            ));
+        }
+    }
+    Ok(trigger)
+}
+
+/// Settings for `uid`, falling back to the system settings (and telling the
+/// user why) when their own config cannot be used.
+fn settings_for_uid(cfg: &Config, uid: u32) -> Settings {
+    match user_settings(cfg, uid) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("morph-bang: using system settings for uid {uid}: {err:#}");
+            let settings = cfg.settings(None);
+            notify_error(
+                &settings,
+                uid,
+                &format!("Ignoring your Morph Bang config: {err:#}"),
+            );
+            settings
+        }
+    }
+}
+
+fn user_settings(cfg: &Config, uid: u32) -> Result<Settings> {
+    let home = home_dir_for_uid(uid)?;
+    let user = UserConfig::load(&home.join(USER_CONFIG_PATH), uid)?;
+    Ok(cfg.settings(user.as_ref()))
+}
+
+/// Handles a trigger on a folder. In safe mode the folder is snapshotted
+/// into its history first. A folder of pages extracted from a PDF and left
+/// unchanged gets that PDF back rather than a rebuilt one.
+fn handle_directory_trigger(job: &Job, trigger: &Trigger, version_dir: &Path) -> Result<()> {
+    let path = job.path;
+    let [target] = trigger.targets.as_slice() else {
+        return Err(anyhow!("folders can only be converted to a single PDF"));
+    };
+    if target.ext != "pdf" {
+        return Err(anyhow!("folders can only be converted to a single PDF"));
+    }
+    if !target.options.is_empty() {
+        return Err(anyhow!(
+            "conversion options are not supported for folders ({})",
+            target.options
+        ));
+    }
+    let clean_path = path.with_extension(&target.ext);
+
+    let original_dir = source_name(job, &folder_source());
+    if original_dir.exists() {
+        return Err(anyhow!(
+            "cannot rename source folder back, path exists: {}",
+            original_dir.display()
+        ));
+    }
 
+    let folder_hash = hash_path(path)?.to_hex().to_string();
+    let history = history_match(version_dir, target, &folder_hash);
+    if !trigger.destructive && job.settings.safe_mode {
+        store_version(
+            job.cfg,
+            path,
+            version_dir,
+            &trigger.origin(&folder_source(), &display_name(&original_dir)),
+            job.owner.uid,
+            job.owner.gid,
+        )?;
+    }
+    let produced = match &history {
+        Some(version) => {
+            restore_version_file(job.cfg, version, &clean_path, job.owner, None)?;
+            Produced::Restored
+        }
+        None => {
+            handle_folder_to_pdf(job, &clean_path)?;
+            Produced::File
+        }
+    };
+    if clean_path.is_file() {
+        record_lineage(
+            job,
+            version_dir,
+            &folder_hash,
+            target,
+            &ConvertOptions::default(),
+            produced,
+        );
+    }
+    fs::rename(path, &original_dir).with_context(|| {
+        format!(
+            "failed to rename source folder {} -> {}",
+            path.display(),
+            original_dir.display()
+        )
+    })?;
+    notify_owner(
+        &job.settings,
+        job.owner.uid,
+        &if history.is_some() {
+            format!(
+                "Restored {} from version history, the pages are unchanged; restored source folder name for {}",
+                display_name(&clean_path),
+                job.filename
+            )
+        } else {
+            format!(
+                "Created {} and restored source folder name for {}",
+                display_name(&clean_path),
+                job.filename
+            )
+        },
+    );
+    Ok(())
+}
+
+fn handle_file_trigger(
+    job: &Job,
+    trigger: &Trigger,
+    source: &Source,
+    version_dir: &Path,
+) -> Result<()> {
+    let Job {
+        path,
+        filename,
+        owner,
+        ..
+    } = *job;
+    let keep_version = !trigger.destructive && job.settings.safe_mode;
+    let current_hash = hash_file(path)?.to_hex().to_string();
+    let original = find_original(version_dir, source, &current_hash);
+    let mut planned: Vec<(&Target, Result<TargetPlan>)> = plan_targets(
+        job,
+        trigger,
+        source,
+        version_dir,
+        original.as_ref(),
+        &current_hash,
+    )?
+    .into_iter()
+    .map(|(target, plan)| {
+        let plan = plan.and_then(|plan| {
+            plan.ok_or_else(|| {
+                anyhow!(
+                    "cannot convert {} to {}",
+                    source.mime,
+                    target.ext.to_uppercase()
+                )
+            })
+        });
+        (target, plan)
+    })
+    .collect();
+
+    if planned.len() == 1 {
+        let (target, plan) = planned.remove(0);
+        let plan = plan?;
+        if keep_version {
+            store_version(
+                job.cfg,
+                path,
+                version_dir,
+                &trigger.origin(source, &display_name(&source_name(job, source))),
+                owner.uid,
+                owner.gid,
+            )?;
+        }
+        if plan.history.is_none() {
+            notify_sync(&job.settings, owner.uid, filename, target, &plan);
+        }
+        let produced = morph_target(job, target, &plan)?;
+        let source_hash = plan.original.map_or(current_hash.as_str(), |o| &o.hash);
+        let options = plan.options(target);
+        record_lineage(job, version_dir, source_hash, target, &options, produced);
+        if keeps_source(trigger) {
+            restore_source_name(job, source);
+        } else {
+            let _ = fs::remove_file(path);
+        }
+        if plan.history.is_some() {
+            notify_restore(&job.settings, owner.uid, filename, &target.ext);
+        }
+        return Ok(());
+    }
+
+    // Several targets: every target is attempted and reported on its own,
+    // and the source is versioned once.
+    if keep_version && planned.iter().any(|(_, plan)| plan.is_ok()) {
+        store_version(
+            job.cfg,
+            path,
+            version_dir,
+            &trigger.origin(source, &display_name(&source_name(job, source))),
+            owner.uid,
+            owner.gid,
+        )?;
+    }
+    notify_owner(
+        &job.settings,
+        owner.uid,
+        &format!(
+            "Syncing {} to {}",
+            filename,
+            trigger
+                .targets
+                .iter()
+                .map(|t| t.ext.to_uppercase())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    );
+
+    let mut report = Vec::new();
+    let mut failed = false;
+    for (target, plan) in planned {
+        let label = target.ext.to_uppercase();
+        let result = plan.and_then(|plan| {
+            let produced = morph_target(job, target, &plan)?;
+            let source_hash = plan.original.map_or(current_hash.as_str(), |o| &o.hash);
+            let options = plan.options(target);
+            record_lineage(job, version_dir, source_hash, target, &options, produced);
+            Ok((produced, plan))
+        });
+        match result {
+            Ok((Produced::Restored, _)) => report.push(format!("{label} restored from history")),
+            Ok((_, plan)) => {
+                let mut line = format!("{label} done");
+                if let Some(original) = plan.original {
+                    line.push_str(&format!(" from {}", original.name));
+                }
+                if let Some(warning) = plan.warning {
+                    line.push_str(&format!(" ({warning})"));
+                }
+                report.push(line);
+            }
+            Err(err) => {
+                eprintln!("morph-bang error for {} ({label}): {err}", path.display());
+                report.push(format!("{label} failed: {err}"));
+                failed = true;
+            }
+        }
+    }
+
+    if !failed && !keeps_source(trigger) {
+        let _ = fs::remove_file(path);
+        notify_owner(
+            &job.settings,
+            owner.uid,
+            &format!("{}: {}", filename, report.join("; ")),
+        );
+        return Ok(());
+    }
+
+    // Keep the source under its real extension rather than the trigger name,
+    // so retrying a failed target does not overwrite the outputs that did
+    // succeed.
+    let kept = restore_source_name(job, source).map_or(filename.to_string(), |p| display_name(&p));
+    let body = format!("{}: {} (kept {})", filename, report.join("; "), kept);
+    if failed {
+        notify_error(&job.settings, owner.uid, &body);
+    } else {
+        notify_owner(&job.settings, owner.uid, &body);
+    }
+    Ok(())
+}
+
+/// Whether converting `target` writes a folder of pages rather than a file:
+/// a PDF rendered to images without a single page chosen. Through a chain
+/// of conversions, or from a stored original, the page count is not known
+/// beforehand and a folder is assumed.
+fn splits_pages(cfg: &Config, input: &Path, plan: &TargetPlan, target: &Target) -> bool {
+    let Some(last) = plan.steps.last() else {
+        return false;
+    };
+    if plan.history.is_some() || last.engine != Engine::Image || last.from_ext != "pdf" {
+        return false;
+    }
+    match plan.options(target).pages {
+        Some((first, last)) => first != last,
+        None if plan.steps.len() == 1 && plan.original.is_none() => {
+            pdf_pages(cfg, input).is_none_or(|pages| pages > 1)
+        }
+        None => true,
+    }
+}
+
+/// Whether the triggered file stays next to its outputs. Taking some pages
+/// of a document leaves the rest only in the source, which `!!` or safe mode
+/// being off would not keep in history.
+fn keeps_source(trigger: &Trigger) -> bool {
+    trigger.targets.iter().any(|t| t.options.pages.is_some())
+}
+
+/// What a folder is recorded as in its history.
+fn folder_source() -> Source {
+    Source {
+        mime: FOLDER_MIME.to_string(),
+        ext: String::new(),
+    }
+}
+
+/// The name the triggered file or folder had before it was triggered: the
+/// one the watcher saw it renamed from, otherwise its base name with the
+/// detected extension, or with the trigger dropped when nothing is known.
+fn source_name(job: &Job, source: &Source) -> PathBuf {
+    let path = job.path;
+    let renamed_from = job
+        .origin
+        .and_then(Path::file_name)
+        .filter(|name| !is_trigger_name(name));
+    if let Some(name) = renamed_from {
+        return path.with_file_name(name);
+    }
     if path.is_dir() {
-        return handle_directory_trigger(path, &clean_path, filename, &trigger, owner);
+        return path.with_extension("");
+    }
+    let ext = if source.ext.is_empty() {
+        source_ext_from_mime(&source.mime)
+    } else {
+        &source.ext
+    };
+    path.with_extension(ext)
+}
+
+/// Renames the triggered file or folder back to its `source_name`, or to a
+/// numbered variant of it when that is taken, so no trigger name is left to
+/// fire again. Returns the new path, or `None` if the rename failed.
+fn restore_source_name(job: &Job, source: &Source) -> Option<PathBuf> {
+    let preferred = source_name(job, source);
+    let original = (1..100)
+        .map(|n| numbered_name(&preferred, n))
+        .find(|p| !p.exists())?;
+    fs::rename(job.path, &original).ok()?;
+    Some(original)
+}
+
+/// `photo.jpg` for 1, then `photo (2).jpg`, `photo (3).jpg`...
+fn numbered_name(path: &Path, n: u32) -> PathBuf {
+    if n == 1 {
+        return path.to_path_buf();
+    }
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match path.extension() {
+        Some(ext) => path.with_file_name(format!("{stem} ({n}).{}", ext.to_string_lossy())),
+        None => path.with_file_name(format!("{stem} ({n})")),
+    }
+}
+
+/// Handles `.!info`: renames the file back, writes what the tools report
+/// about it to `<name>.info.json` and notifies a one-line summary.
+fn handle_info(job: &Job) -> Result<()> {
+    let Job { cfg, path, .. } = *job;
+    if !path.is_file() {
+        return Err(anyhow!("info is only available for files"));
+    }
+    let source = Source::detect(cfg, path)?;
+    let restored = restore_source_name(job, &source);
+    let current = restored.as_deref().unwrap_or(path);
+    let info = info::gather(cfg, current, &source.mime, &source.ext)
+        .with_context(|| format!("failed to inspect {}", current.display()))?;
+
+    let mut body = serde_json::to_string_pretty(&info)?;
+    body.push('\n');
+    // Readable by whoever can read the file, but never executable, even
+    // when the file is.
+    write_sidecar(
+        &path.with_extension("info.json"),
+        &body,
+        job.owner,
+        job.owner.mode & 0o666,
+    )?;
+    notify_owner(&job.settings, job.owner.uid, &info.summary());
+    Ok(())
+}
+
+/// Handles `.!undo`: puts back the most recent version whose content differs
+/// from the file or folder, under the extension and permissions it was
+/// stored with. The current content is stored first, so undoing again swaps
+/// back, and a folder can be swapped for the file it was converted from.
+fn handle_undo(job: &Job, trigger: &Trigger, source: &Source, version_dir: &Path) -> Result<()> {
+    let Job { path, owner, .. } = *job;
+    let is_folder = path.is_dir();
+    let current_hash = hash_path(path)?;
+    let previous = latest_version_where(version_dir, |version| {
+        hash_version(version).is_ok_and(|hash| hash != current_hash)
+    });
+    let Some(previous) = previous else {
+        restore_source_name(job, source);
+        return Err(anyhow!("no earlier version in history"));
+    };
+
+    let destination = undo_destination(path, version_dir, &previous);
+    if destination.exists() {
+        restore_source_name(job, source);
+        return Err(anyhow!(
+            "{} already exists, move it away to undo",
+            display_name(&destination)
+        ));
+    }
+
+    store_version(
+        job.cfg,
+        path,
+        version_dir,
+        &trigger.origin(source, &display_name(&source_name(job, source))),
+        owner.uid,
+        owner.gid,
+    )?;
+    restore_version_file(job.cfg, &previous, &destination, owner, None)?;
+    tag_history(&destination, version_dir);
+    let saved = if is_folder {
+        let _ = fs::remove_dir_all(path);
+        "folder".to_string()
+    } else {
+        let _ = fs::remove_file(path);
+        if source.ext.is_empty() {
+            "current version".to_string()
+        } else {
+            format!("{} version", source.ext.to_uppercase())
+        }
+    };
+    notify_owner(
+        &job.settings,
+        owner.uid,
+        &format!(
+            "Restored {} from version history, the {saved} was saved",
+            display_name(&destination)
+        ),
+    );
+    Ok(())
+}
+
+/// Where undo puts `version`: under the name it had before it was
+/// triggered, next to `path`. Versions stored before those names were
+/// recorded get the base name with their stored extension.
+fn undo_destination(path: &Path, version_dir: &Path, version: &Path) -> PathBuf {
+    let manifest = Manifest::load(version_dir).unwrap_or_default();
+    let recorded = manifest
+        .record(version)
+        .map(|r| Path::new(&r.original_name))
+        .filter(|name| {
+            let mut parts = name.components();
+            matches!(parts.next(), Some(Component::Normal(_))) && parts.next().is_none()
+        })
+        .filter(|name| !is_trigger_name(name.as_os_str()));
+    if let Some(name) = recorded {
+        return path.with_file_name(name);
+    }
+    if is_folder_version(version) {
+        path.with_extension("")
+    } else {
+        path.with_extension(canonical_ext(version_ext(version).unwrap_or_default()))
+    }
+}
+
+/// Handles `.!?<ext>`: reports, per target, the engines a conversion would
+/// run and whether history would be restored instead, then renames the file
+/// back without touching its contents or the version store.
+fn handle_preview(job: &Job, trigger: &Trigger, source: &Source, version_dir: &Path) -> Result<()> {
+    let path = job.path;
+
+    let mut lines = vec![format!(
+        "{} is {} ({})",
+        job.filename,
+        source.mime,
+        if source.ext.is_empty() {
+            "unknown extension".to_string()
+        } else {
+            source.ext.to_uppercase()
+        }
+    )];
+    let current_hash = hash_file(path)?.to_hex().to_string();
+    let original = find_original(version_dir, source, &current_hash);
+    let planned = match plan_targets(
+        job,
+        trigger,
+        source,
+        version_dir,
+        original.as_ref(),
+        &current_hash,
+    ) {
+        Ok(planned) => planned,
+        Err(err) => {
+            lines.push(format!("Refused: {err}"));
+            Vec::new()
+        }
+    };
+    let mut any_ok = false;
+    for (target, plan) in planned {
+        let label = target.ext.to_uppercase();
+        let line = match plan {
+            Ok(Some(plan)) => {
+                any_ok = true;
+                let opts = plan.options(target).with_defaults(&job.settings.quality);
+                let line = match (&plan.history, plan.original) {
+                    (Some(version), _) => format!(
+                        "{label}: restore {} from version history",
+                        display_name(version)
+                    ),
+                    (None, Some(original)) => format!(
+                        "{label}: {} from {} in version history",
+                        describe_steps(&plan.steps, &opts),
+                        original.name
+                    ),
+                    (None, None) => format!("{label}: {}", describe_steps(&plan.steps, &opts)),
+                };
+                match plan.warning {
+                    Some(warning) => format!("{line} ({warning})"),
+                    None => line,
+                }
+            }
+            Ok(None) => format!("{label}: not supported from {}", source.mime),
+            Err(err) => format!("{label}: {err}"),
+        };
+        lines.push(line);
+    }
+    if any_ok {
+        lines.push(if !trigger.destructive && job.settings.safe_mode {
+            "The original would be kept in version history".to_string()
+        } else {
+            "The original would not be kept in version history".to_string()
+        });
+    }
+
+    if restore_source_name(job, source).is_none() {
+        lines.push(format!("{} kept its name", job.filename));
+    }
+    report_preview(job, &lines)
+}
+
+fn handle_directory_preview(job: &Job, trigger: &Trigger, version_dir: &Path) -> Result<()> {
+    let path = job.path;
+    let mut lines = Vec::new();
+    let mut any_ok = false;
+    for target in &trigger.targets {
+        let label = target.ext.to_uppercase();
+        if target.ext != "pdf" || trigger.targets.len() > 1 {
+            lines.push(format!(
+                "{label}: folders can only be converted to a single PDF"
+            ));
+        } else if !target.options.is_empty() {
+            lines.push(format!(
+                "{label}: conversion options are not supported for folders"
+            ));
+        } else if let Some(version) = hash_path(path)
+            .ok()
+            .and_then(|hash| history_match(version_dir, target, &hash.to_hex()))
+        {
+            any_ok = true;
+            lines.push(format!(
+                "{label}: pages unchanged, restore {} from version history",
+                display_name(&version)
+            ));
+        } else {
+            any_ok = true;
+            let files = gather_folder_inputs(job.cfg, path);
+            lines.push(format!(
+                "{label}: merge {} files into {} ({}, {})",
+                files.len(),
+                display_name(&path.with_extension("pdf")),
+                job.settings.pdf.page_size,
+                job.settings.pdf.profile
+            ));
+        }
+    }
+
+    if any_ok {
+        lines.push(if !trigger.destructive && job.settings.safe_mode {
+            "The folder would be kept in version history".to_string()
+        } else {
+            "The folder would not be kept in version history".to_string()
+        });
+    }
+
+    if restore_source_name(job, &folder_source()).is_none() {
+        lines.push(format!("{} kept its name", job.filename));
+    }
+    report_preview(job, &lines)
+}
+
+/// Sends a preview to the owner and, if they asked for it, writes it next
+/// to the previewed file as `<name>.preview.txt`.
+fn report_preview(job: &Job, lines: &[String]) -> Result<()> {
+    notify_owner(&job.settings, job.owner.uid, &lines.join("\n"));
+    if !job.settings.preview_sidecar {
+        return Ok(());
+    }
+    let mut body = lines.join("\n");
+    body.push('\n');
+    write_sidecar(
+        &job.path.with_extension("preview.txt"),
+        &body,
+        job.owner,
+        0o644,
+    )
+}
+
+/// Describes a plan in words, e.g. `pandoc to PDF, then vips`.
+fn describe_steps(steps: &[Step], opts: &ConvertOptions) -> String {
+    let mut parts = Vec::new();
+    for (idx, step) in steps.iter().enumerate() {
+        let last = idx + 1 == steps.len();
+        let mut part = match step.engine {
+            Engine::Image => "vips".to_string(),
+            Engine::Document => "pandoc".to_string(),
+            Engine::Media if is_image_output(&step.to_ext) => "ffmpeg frame grab".to_string(),
+            Engine::Media if copies_streams(&step.from_ext, &step.to_ext, opts) => {
+                "ffmpeg remux (stream copy, re-encoding only if that fails)".to_string()
+            }
+            Engine::Media => "ffmpeg re-encode".to_string(),
+        };
+        if !last {
+            part.push_str(&format!(" to {}", step.to_ext.to_uppercase()));
+        } else if !opts.is_empty() {
+            part.push_str(&format!(" [{opts}]"));
+        }
+        parts.push(part);
+    }
+    parts.join(", then ")
+}
+
+/// Plans how `target` is produced from `source`. `Ok(None)` means no engine
+/// or chain of engines knows the pair; an error means the requested options
+/// do not fit the final step.
+fn check_target(source: &Source, target: &Target) -> Result<Option<Vec<Step>>> {
+    let Some(steps) = pipeline::plan(&source.mime, &source.ext, &target.ext) else {
+        return Ok(None);
+    };
+    if let Some(last) = steps.last() {
+        check_options(
+            &target.options,
+            last.engine,
+            &last.from_mime,
+            &last.from_ext,
+            &target.ext,
+        )?;
+    }
+    Ok(Some(steps))
+}
+
+/// Plans each target of `trigger` as the conversion would run it, lossy
+/// warnings included; `None` marks a target `source` cannot be converted
+/// to. Fails as a whole when more than one target would split a PDF into
+/// pages.
+fn plan_targets<'a, 't>(
+    job: &Job,
+    trigger: &'t Trigger,
+    source: &Source,
+    version_dir: &Path,
+    original: Option<&'a Original>,
+    current_hash: &str,
+) -> Result<Vec<PlannedTarget<'a, 't>>> {
+    let planned: Vec<_> =
+        trigger
+            .targets
+            .iter()
+            .map(|target| {
+                let plan = plan_target(version_dir, source, original, target, current_hash)
+                    .and_then(|plan| match plan {
+                        Some(plan) => check_lossy(job, trigger, source, target, &plan)
+                            .map(|warning| Some(TargetPlan { warning, ..plan })),
+                        None => Ok(None),
+                    });
+                (target, plan)
+            })
+            .collect();
+    let splitting = planned
+        .iter()
+        .filter(|(target, plan)| {
+            matches!(plan, Ok(Some(plan)) if splits_pages(job.cfg, job.path, plan, target))
+        })
+        .count();
+    if splitting > 1 {
+        return Err(anyhow!(SPLIT_TARGETS_ERROR));
+    }
+    Ok(planned)
+}
+
+/// Plans `target` for the triggered file: restored from history when a
+/// linked version exists, otherwise converted, from `original` if that can
+/// produce it. `Ok(None)` means the file itself cannot be converted.
+fn plan_target<'a>(
+    version_dir: &Path,
+    source: &Source,
+    original: Option<&'a Original>,
+    target: &Target,
+    current_hash: &str,
+) -> Result<Option<TargetPlan<'a>>> {
+    let Some(steps) = check_target(source, target)? else {
+        return Ok(None);
+    };
+    let history = history_match(version_dir, target, current_hash);
+    if history.is_none() {
+        if let Some(original) = original {
+            let replayed = Target {
+                ext: target.ext.clone(),
+                options: original.options.clone().overridden_by(&target.options),
+            };
+            if let Ok(Some(steps)) = check_target(&original.source, &replayed) {
+                return Ok(Some(TargetPlan {
+                    steps,
+                    history: None,
+                    original: Some(original),
+                    warning: None,
+                }));
+            }
+        }
+    }
+    Ok(Some(TargetPlan {
+        steps,
+        history,
+        original: None,
+        warning: None,
+    }))
+}
+
+/// Applies the `lossy` setting to a safe conversion that re-encodes one
+/// lossy format into another. Returns the warning to show, or an error when
+/// the setting wants the trigger to name options first.
+fn check_lossy(
+    job: &Job,
+    trigger: &Trigger,
+    source: &Source,
+    target: &Target,
+    plan: &TargetPlan,
+) -> Result<Option<String>> {
+    if trigger.destructive || !job.settings.safe_mode || plan.history.is_some() {
+        return Ok(None);
+    }
+    let from = plan.original.map_or(source, |o| &o.source);
+    if !is_lossy(&from.ext) || !is_lossy(&target.ext) {
+        return Ok(None);
+    }
+    let opts = plan.options(target);
+    let remux = plan.steps.iter().all(|step| {
+        step.engine == Engine::Media && copies_streams(&step.from_ext, &step.to_ext, &opts)
+    });
+    if remux {
+        return Ok(None);
+    }
+    let loss = format!(
+        "{} to {} re-encodes lossy data",
+        from.ext.to_uppercase(),
+        target.ext.to_uppercase()
+    );
+    match job.settings.lossy {
+        LossyPolicy::Allow => Ok(None),
+        LossyPolicy::Warn => Ok(Some(loss)),
+        LossyPolicy::Confirm if !target.options.is_empty() => Ok(None),
+        LossyPolicy::Confirm => {
+            let example = if is_audio_output(&target.ext) {
+                "192k"
+            } else if is_video_container(&target.ext) {
+                "crf23"
+            } else {
+                "q90"
+            };
+            Err(anyhow!(
+                "{loss}, name an option such as .!{}@{example} or use .!!{}",
+                target.ext,
+                target.ext
+            ))
+        }
     }
+}
 
-    if !path.is_file() {
-        return Ok(());
+/// The stored ancestor to convert from when the file is itself a lossy
+/// conversion, so the output is not an encode of an encode: the nearest
+/// lossless one, otherwise the first the history has. Only ancestors whose
+/// conversions down to the file can be replayed qualify: the pages and size
+/// they chose are applied again, while their encoder settings only shaped
+/// the lossy copy being skipped. A conversion recorded without its options,
+/// or two page selections in a row, ends the search.
+fn find_original(version_dir: &Path, source: &Source, current_hash: &str) -> Option<Original> {
+    if !is_lossy(&source.ext) {
+        return None;
     }
-
-    let version_dir = version_dir_for_path(&clean_path, owner.uid)?;
-    ensure_version_paths_owned(&version_dir, owner.uid, owner.gid)?;
-    handle_file_trigger(path, &clean_path, filename, &trigger, &version_dir, owner)
+    let mut replay = ConvertOptions::default();
+    let mut usable = Vec::new();
+    for ancestor in ancestors(version_dir, current_hash) {
+        let Some(link) = ancestor.options.as_deref().and_then(|spec| {
+            if spec.is_empty() {
+                Some(ConvertOptions::default())
+            } else {
+                ConvertOptions::parse(spec).ok()
+            }
+        }) else {
+            break;
+        };
+        let Some(combined) = link.content().then(&replay) else {
+            break;
+        };
+        if ancestor.ext == FOLDER_EXT {
+            break;
+        }
+        replay = combined;
+        usable.push((ancestor, replay.clone()));
+    }
+    let index = usable
+        .iter()
+        .position(|(a, _)| !is_lossy(&a.ext))
+        .or(usable.len().checked_sub(1))?;
+    let (best, options) = usable.swap_remove(index);
+    Some(Original {
+        name: if best.original_name.is_empty() {
+            display_name(&best.version)
+        } else {
+            best.original_name
+        },
+        source: Source {
+            mime: best.mime,
+            ext: canonical_ext(&best.ext).to_string(),
+        },
+        version: best.version,
+        hash: best.hash,
+        options,
+    })
 }
 
-fn handle_directory_trigger(
-    path: &Path,
-    clean_path: &Path,
-    filename: &str,
-    trigger: &Trigger,
-    owner: Owner,
-) -> Result<()> {
-    if trigger.target_ext != "pdf" {
-        return Ok(());
+/// Marks `path` as belonging to `version_dir`, so its history follows it
+/// when it is moved. Filesystems without user xattrs fall back to the path.
+fn tag_history(path: &Path, version_dir: &Path) {
+    match tag_lineage(path, version_dir) {
+        Ok(()) => {}
+        Err(err) if err.raw_os_error() == Some(Errno::ENOTSUP as i32) => {}
+        Err(err) => eprintln!("morph-bang: failed to tag {}: {err}", path.display()),
     }
+}
 
-    let original_dir = path.with_extension("");
-    if original_dir.exists() {
-        return Err(anyhow!(
-            "cannot rename source folder back, path exists: {}",
-            original_dir.display()
-        ));
+/// The version a target would be restored from instead of converting: one
+/// the current content `current_hash` was converted to or from. An explicit
+/// option asks for a specific encoding, which an old version from history
+/// would not honour.
+fn history_match(version_dir: &Path, target: &Target, current_hash: &str) -> Option<PathBuf> {
+    if !target.options.is_empty() {
+        return None;
     }
-
-    handle_folder_to_pdf(path, clean_path)?;
-    fs::rename(path, &original_dir).with_context(|| {
-        format!(
-            "failed to rename source folder {} -> {}",
-            path.display(),
-            original_dir.display()
-        )
-    })?;
-    notify_owner(
-        owner.uid,
-        &format!(
-            "Created {} and restored source folder name for {}",
-            clean_path
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("output.pdf"),
-            filename
-        ),
-    );
-    Ok(())
+    find_lineage_version(version_dir, &target.ext, current_hash)
 }
 
-fn handle_file_trigger(
-    path: &Path,
-    clean_path: &Path,
-    filename: &str,
-    trigger: &Trigger,
+/// Records that the file or folder of pages written for `target` came from
+/// `source_hash` with `options`, so triggering it back restores the source
+/// rather than converting again, and tags it with the history it belongs
+/// to. A restore is not a conversion and only gets the tag.
+fn record_lineage(
+    job: &Job,
     version_dir: &Path,
-    owner: Owner,
-) -> Result<()> {
-    let mime = detect_mime(path)?;
-    let source_ext = detect_source_ext(path);
-
-    if !is_valid_target(&mime, &trigger.target_ext) {
-        return Ok(());
+    source_hash: &str,
+    target: &Target,
+    options: &ConvertOptions,
+    produced: Produced,
+) {
+    let output = match produced {
+        Produced::Folder => job.path.with_extension(""),
+        _ => job.path.with_extension(&target.ext),
+    };
+    tag_history(&output, version_dir);
+    if produced == Produced::Restored {
+        return;
     }
-
-    if let Some(existing) = find_latest_version_by_ext(version_dir, &trigger.target_ext) {
-        if !trigger.destructive {
-            store_version(path, version_dir, &source_ext, owner.uid, owner.gid)?;
-        }
-        restore_version_file(&existing, clean_path, owner, Some(owner.mode))?;
-        let _ = fs::remove_file(path);
-        notify_restore(owner.uid, filename, &trigger.target_ext);
-        return Ok(());
+    let result = hash_path(&output).and_then(|hash| {
+        record_conversion(
+            version_dir,
+            source_hash,
+            &hash.to_hex(),
+            &options.to_string(),
+            job.owner.uid,
+            job.owner.gid,
+        )
+    });
+    if let Err(err) = result {
+        eprintln!("morph-bang: lineage for {}: {err:#}", output.display());
     }
+}
 
-    if !trigger.destructive {
-        store_version(path, version_dir, &source_ext, owner.uid, owner.gid)?;
+/// Writes one target next to the source, restored from history or
+/// converted from the source or its stored original. The source itself is
+/// left in place.
+fn morph_target(job: &Job, target: &Target, plan: &TargetPlan) -> Result<Produced> {
+    let Job {
+        cfg, path, owner, ..
+    } = *job;
+    let clean_path = path.with_extension(&target.ext);
+    if let Some(existing) = &plan.history {
+        restore_version_file(cfg, existing, &clean_path, owner, Some(owner.mode))?;
+        return Ok(Produced::Restored);
     }
 
-    notify_sync(owner.uid, filename, &trigger.target_ext);
+    // The original is copied out of the store first: it may be compressed,
+    // and a PDF expands into a folder of pages next to its input.
+    let workspace = match plan.original {
+        Some(_) => Some(create_workspace("original", path, owner.uid, owner.gid)?),
+        None => None,
+    };
+    let input = match (plan.original, &workspace) {
+        (Some(original), Some(workspace)) => {
+            let input = workspace.join(format!("original.{}", original.source.ext));
+            restore_version_file(cfg, &original.version, &input, owner, Some(owner.mode))?;
+            input
+        }
+        _ => path.to_path_buf(),
+    };
 
-    let temp_file = path.with_extension(format!("morph_tmp.{}", trigger.target_ext));
-    let status = morph_engine(path, &temp_file, &trigger.target_ext, &source_ext, &mime)?;
-    if status == 0 {
+    let temp_file = path.with_extension(format!("morph_tmp.{}", target.ext));
+    let opts = plan.options(target).with_defaults(&job.settings.quality);
+    let status = match plan.steps.as_slice() {
+        [step] => morph_engine(
+            cfg,
+            &input,
+            &temp_file,
+            &target.ext,
+            &step.from_ext,
+            &step.from_mime,
+            &opts,
+        ),
+        steps => pipeline::run(job, &input, steps, &temp_file, &opts),
+    };
+    let result = status.and_then(|status| {
+        if status == 2 {
+            if input != path {
+                pipeline::move_folder(&input.with_extension(""), &path.with_extension(""))?;
+            }
+            return Ok(Produced::Folder);
+        }
         copy_owner_and_perms(path, &temp_file)?;
-        fs::rename(&temp_file, clean_path)?;
-        let _ = fs::remove_file(path);
-    } else if status == 2 {
-        let _ = fs::remove_file(&temp_file);
-    }
+        fs::rename(&temp_file, &clean_path)?;
+        Ok(Produced::File)
+    });
     let _ = fs::remove_file(&temp_file);
-    Ok(())
+    if let Some(workspace) = workspace {
+        let _ = fs::remove_dir_all(workspace);
+    }
+    result
 }
 
-fn notify_restore(uid: u32, filename: &str, target_ext: &str) {
+fn display_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn notify_restore(settings: &Settings, uid: u32, filename: &str, target_ext: &str) {
     notify_owner(
+        settings,
         uid,
         &format!(
             "Restored {} from version history ({})",
@@ -249,40 +1495,30 @@ fn notify_restore(uid: u32, filename: &str, target_ext: &str) {
     );
 }
 
-fn notify_sync(uid: u32, filename: &str, target_ext: &str) {
-    notify_owner(
-        uid,
-        &format!("Syncing {} to {}", filename, target_ext.to_uppercase()),
-    );
-}
-
-fn restore_version_file(
-    version_file: &Path,
-    destination: &Path,
-    owner: Owner,
-    mode_override: Option<u32>,
-) -> Result<()> {
-    fs::copy(version_file, destination).with_context(|| {
-        format!(
-            "failed to restore version {} -> {}",
-            version_file.display(),
-            destination.display()
-        )
-    })?;
-    owner.chown(destination)?;
-    let mode = match mode_override {
-        Some(v) => v,
-        None => fs::metadata(version_file)
-            .map(|m| m.permissions().mode())
-            .unwrap_or(0o644),
+fn notify_sync(settings: &Settings, uid: u32, filename: &str, target: &Target, plan: &TargetPlan) {
+    let mut label = match pipeline::via(&plan.steps) {
+        Some(via) => format!("{} via {}", target.ext.to_uppercase(), via),
+        None => target.ext.to_uppercase(),
     };
-    fs::set_permissions(destination, fs::Permissions::from_mode(mode))?;
-    Ok(())
+    if let Some(original) = plan.original {
+        label.push_str(&format!(" from {} in version history", original.name));
+    }
+    let mut body = if target.options.is_empty() {
+        format!("Syncing {} to {}", filename, label)
+    } else {
+        format!("Syncing {} to {} ({})", filename, label, target.options)
+    };
+    if let Some(warning) = &plan.warning {
+        body.push_str(&format!(", {warning}"));
+    }
+    notify_owner(settings, uid, &body);
 }
 
-fn handle_folder_to_pdf(input_dir: &Path, output_pdf: &Path) -> Result<()> {
+fn handle_folder_to_pdf(job: &Job, output_pdf: &Path) -> Result<()> {
+    let cfg = job.cfg;
+    let input_dir = job.path;
     let owner = Owner::from_path(input_dir)?;
-    let workspace = create_pdf_workspace(input_dir, owner.uid, owner.gid)?;
+    let workspace = create_workspace("pdf", input_dir, owner.uid, owner.gid)?;
     let temp_dir = workspace.join("pages");
     let final_tmp = workspace.join("merged.pdf");
     let normalized_tmp = workspace.join("normalized.pdf");
@@ -291,30 +1527,31 @@ fn handle_folder_to_pdf(input_dir: &Path, output_pdf: &Path) -> Result<()> {
         fs::create_dir_all(&temp_dir)?;
         owner.chown(&temp_dir)?;
 
-        let files = gather_folder_inputs(input_dir);
+        let files = gather_folder_inputs(cfg, input_dir);
         if files.is_empty() {
             return Ok(());
         }
 
         notify_owner(
+            &job.settings,
             owner.uid,
             &format!("Creating PDF from {} files", files.len()),
         );
 
         for (idx, file) in files.iter().enumerate() {
             let page = temp_dir.join(format!("{:04}.pdf", idx + 1));
-            let mime = detect_mime(file).unwrap_or_default();
-            let src_ext = detect_source_ext(file);
+            let mime = detect_mime(cfg, file).unwrap_or_default();
+            let src_ext = detect_source_ext(cfg, file);
             if mime == "application/pdf" {
                 fs::copy(file, &page)?;
                 owner.chown(&page)?;
             } else if mime.starts_with("image/") {
-                run_cmd(Command::new("magick").arg(file).arg(&page))?;
+                run_cmd(Command::new(&cfg.engines.magick).arg(file).arg(&page))?;
                 owner.chown(&page)?;
             } else {
                 let from = pandoc_from_ext(&src_ext);
                 run_cmd(
-                    Command::new("pandoc")
+                    Command::new(&cfg.engines.pandoc)
                         .arg("-f")
                         .arg(from)
                         .arg(file)
@@ -340,14 +1577,14 @@ fn handle_folder_to_pdf(input_dir: &Path, output_pdf: &Path) -> Result<()> {
             return Ok(());
         }
 
-        let mut cmd = Command::new("pdfunite");
+        let mut cmd = Command::new(&cfg.engines.pdfunite);
         for page in &pdf_pages {
             cmd.arg(page);
         }
         cmd.arg(&final_tmp);
         run_cmd(&mut cmd)?;
 
-        normalize_and_compress_pdf(&final_tmp, &normalized_tmp)?;
+        normalize_and_compress_pdf(cfg, &job.settings, &final_tmp, &normalized_tmp)?;
         chown_path(&normalized_tmp, owner.uid, owner.gid)?;
         fs::set_permissions(&normalized_tmp, fs::Permissions::from_mode(0o644))?;
         fs::rename(&normalized_tmp, output_pdf)?;
@@ -358,11 +1595,16 @@ fn handle_folder_to_pdf(input_dir: &Path, output_pdf: &Path) -> Result<()> {
     result
 }
 
-fn normalize_and_compress_pdf(input_pdf: &Path, output_pdf: &Path) -> Result<()> {
-    let mut cmd = Command::new("gs");
+fn normalize_and_compress_pdf(
+    cfg: &Config,
+    settings: &Settings,
+    input_pdf: &Path,
+    output_pdf: &Path,
+) -> Result<()> {
+    let mut cmd = Command::new(&cfg.engines.gs);
     cmd.arg("-sDEVICE=pdfwrite")
         .arg("-dCompatibilityLevel=1.6")
-        .arg(format!("-dPDFSETTINGS={}", PDF_OUTPUT_PROFILE))
+        .arg(format!("-dPDFSETTINGS={}", settings.pdf.profile))
         .arg("-dAutoRotatePages=/None")
         .arg("-dDetectDuplicateImages=true")
         .arg("-dCompressFonts=true")
@@ -372,142 +1614,27 @@ fn normalize_and_compress_pdf(input_pdf: &Path, output_pdf: &Path) -> Result<()>
         .arg("-dBATCH")
         .arg("-dFIXEDMEDIA")
         .arg("-dPDFFitPage")
-        .arg(format!("-sPAPERSIZE={}", PDF_OUTPUT_PAGE_SIZE))
+        .arg(format!("-sPAPERSIZE={}", settings.pdf.page_size))
         .arg(format!("-sOutputFile={}", output_pdf.display()))
         .arg(input_pdf);
     run_cmd(&mut cmd)
 }
 
-fn create_pdf_workspace(input_dir: &Path, uid: u32, gid: u32) -> Result<PathBuf> {
-    let ts = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .context("clock error")?
-        .as_nanos();
-    let pid = std::process::id();
-    let mut hasher = blake3::Hasher::new();
-    hasher.update(input_dir.as_os_str().as_bytes());
-    let tag = hasher.finalize().to_hex();
-    let path = std::env::temp_dir().join(format!("morph-bang-pdf-{pid}-{ts}-{}", &tag[..12]));
-    fs::create_dir_all(&path)?;
-    chown_path(&path, uid, gid)?;
-    Ok(path)
-}
-
-fn gather_folder_inputs(dir: &Path) -> Vec<PathBuf> {
+fn gather_folder_inputs(cfg: &Config, dir: &Path) -> Vec<PathBuf> {
     let mut files: Vec<PathBuf> = WalkDir::new(dir)
         .max_depth(1)
         .into_iter()
         .filter_map(|e| e.ok())
         .map(|e| e.into_path())
         .filter(|p| p.is_file())
-        .filter(|p| is_supported_folder_input(p))
+        .filter(|p| is_supported_folder_input(cfg, p))
         .collect();
     files.sort();
     files
 }
 
-fn morph_engine(
-    input: &Path,
-    out: &Path,
-    target_ext: &str,
-    source_ext: &str,
-    mime: &str,
-) -> Result<i32> {
-    if mime.starts_with("image/") || mime == "application/pdf" || mime == "application/postscript" {
-        if source_ext == "pdf" {
-            let pages = pdf_pages(input).unwrap_or(1);
-            if pages > 1 {
-                let dir_path = input.with_extension("");
-                fs::create_dir_all(&dir_path)?;
-                let input_meta = fs::metadata(input)?;
-                chown_path(&dir_path, input_meta.uid(), input_meta.gid())?;
-                let mut success = false;
-                for i in 0..pages {
-                    let page_file = dir_path.join(format!("{:03}.{}", i + 1, target_ext));
-                    let in_arg = format!("{}[dpi=300,page={}]", input.display(), i);
-                    if run_cmd(Command::new("vips").arg("copy").arg(in_arg).arg(&page_file)).is_ok()
-                    {
-                        if copy_owner_and_perms(input, &page_file).is_ok() {
-                            success = true;
-                        } else {
-                            let _ = fs::remove_file(&page_file);
-                        }
-                    }
-                }
-                if success {
-                    let _ = fs::remove_file(input);
-                    return Ok(2);
-                }
-            }
-        }
-        if matches!(source_ext, "svg" | "svgz" | "eps" | "ai" | "pdf") {
-            let in_arg = format!("{}[dpi=300,scale=2]", input.display());
-            if run_cmd(Command::new("vips").arg("copy").arg(in_arg).arg(out)).is_ok() {
-                return Ok(0);
-            }
-        }
-        run_cmd(Command::new("vips").arg("copy").arg(input).arg(out))?;
-        return Ok(0);
-    }
-
-    if mime.starts_with("video/") || mime.starts_with("audio/") {
-        if run_cmd(
-            Command::new("ffmpeg")
-                .arg("-y")
-                .arg("-i")
-                .arg(input)
-                .arg("-c")
-                .arg("copy")
-                .arg("-map")
-                .arg("0")
-                .arg("-hide_banner")
-                .arg("-loglevel")
-                .arg("error")
-                .arg(out),
-        )
-        .is_ok()
-        {
-            return Ok(0);
-        }
-        run_cmd(
-            Command::new("ffmpeg")
-                .arg("-y")
-                .arg("-i")
-                .arg(input)
-                .arg("-hide_banner")
-                .arg("-loglevel")
-                .arg("error")
-                .arg(out),
-        )?;
-        return Ok(0);
-    }
-
-    if is_doc_output(target_ext) {
-        let from = pandoc_from_ext(source_ext);
-        let mut cmd = Command::new("pandoc");
-        cmd.arg("-f").arg(from).arg(input).arg("-s");
-        if target_ext == "pdf" {
-            cmd.arg("--pdf-engine=xelatex");
-        } else {
-            cmd.arg("--mathjax");
-        }
-        cmd.arg("-o").arg(out);
-        run_cmd(&mut cmd)?;
-        return Ok(0);
-    }
-
-    Err(anyhow!("unsupported conversion"))
-}
-
-fn copy_owner_and_perms(src: &Path, dst: &Path) -> Result<()> {
-    let meta = fs::metadata(src)?;
-    chown_path(dst, meta.uid(), meta.gid())?;
-    fs::set_permissions(dst, fs::Permissions::from_mode(meta.permissions().mode()))?;
-    Ok(())
-}
-
-fn detect_mime(path: &Path) -> Result<String> {
-    let out = Command::new("file")
+fn detect_mime(cfg: &Config, path: &Path) -> Result<String> {
+    let out = Command::new(&cfg.engines.file)
         .arg("--mime-type")
         .arg("-b")
         .arg(path)
@@ -519,8 +1646,8 @@ fn detect_mime(path: &Path) -> Result<String> {
     Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
 }
 
-fn detect_source_ext(path: &Path) -> String {
-    let out = Command::new("file")
+fn detect_source_ext(cfg: &Config, path: &Path) -> String {
+    let out = Command::new(&cfg.engines.file)
         .arg("--extension")
         .arg("-b")
         .arg(path)
@@ -533,7 +1660,7 @@ fn detect_source_ext(path: &Path) -> String {
             .unwrap_or("")
             .trim_end_matches('?')
             .to_lowercase(),
-        _ => detect_mime(path)
+        _ => detect_mime(cfg, path)
             .ok()
             .map(|m| source_ext_from_mime(&m).to_string())
             .unwrap_or_default(),
@@ -577,33 +1704,31 @@ fn source_ext_from_mime(mime: &str) -> &'static str {
     ""
 }
 
-fn is_supported_folder_input(path: &Path) -> bool {
-    let mime = match detect_mime(path) {
+fn is_supported_folder_input(cfg: &Config, path: &Path) -> bool {
+    let mime = match detect_mime(cfg, path) {
         Ok(m) => m,
         Err(_) => return false,
     };
     if mime.starts_with("image/") || mime == "application/pdf" {
         return true;
     }
-    let source_ext = detect_source_ext(path);
+    let source_ext = detect_source_ext(cfg, path);
     is_doc_folder_ext(&source_ext)
 }
 
-fn pdf_pages(path: &Path) -> Option<u32> {
-    let out = Command::new("pdfinfo").arg(path).output().ok()?;
-    if !out.status.success() {
-        return None;
+fn notify_owner(settings: &Settings, uid: u32, body: &str) {
+    if settings.notifications == Verbosity::All {
+        send_notification(uid, body);
     }
-    let s = String::from_utf8_lossy(&out.stdout);
-    for line in s.lines() {
-        if let Some(rest) = line.strip_prefix("Pages:") {
-            return rest.trim().parse().ok();
-        }
+}
+
+fn notify_error(settings: &Settings, uid: u32, body: &str) {
+    if settings.notifications != Verbosity::None {
+        send_notification(uid, body);
     }
-    None
 }
 
-fn notify_owner(uid: u32, body: &str) {
+fn send_notification(uid: u32, body: &str) {
     if uid == 0 {
         return;
     }
@@ -639,264 +1764,78 @@ fn notify_owner(uid: u32, body: &str) {
     }
 }
 
-fn run_cmd(cmd: &mut Command) -> Result<()> {
-    let out = cmd.output()?;
-    if out.status.success() {
-        Ok(())
-    } else {
-        let stderr = String::from_utf8_lossy(&out.stderr).trim().to_string();
-        if stderr.is_empty() {
-            Err(anyhow!("command failed"))
-        } else {
-            Err(anyhow!("command failed: {stderr}"))
-        }
-    }
-}
-
-fn is_locked(locks: &HashMap<PathBuf, Instant>, key: &Path) -> bool {
-    locks.get(key).is_some_and(|ts| ts.elapsed() < LOCK_TTL)
+fn is_locked(locks: &HashMap<PathBuf, Instant>, key: &Path, ttl: Duration) -> bool {
+    locks.get(key).is_some_and(|ts| ts.elapsed() < ttl)
 }
 
 fn lock(locks: &mut HashMap<PathBuf, Instant>, key: PathBuf) {
     locks.insert(key, Instant::now());
 }
 
-fn prune_locks(locks: &mut HashMap<PathBuf, Instant>) {
-    locks.retain(|_, ts| ts.elapsed() < LOCK_TTL);
-}
-
-fn is_valid_target(mime: &str, ext: &str) -> bool {
-    if mime.starts_with("image/") || mime == "application/postscript" || mime == "application/pdf" {
-        return is_image_output(ext) || is_doc_output(ext);
-    }
-    if mime.starts_with("video/") {
-        return is_media_output(ext) || is_image_output(ext);
-    }
-    if mime.starts_with("audio/") {
-        return is_media_output(ext);
-    }
-    if mime.starts_with("text/")
-        || mime == "application/pdf"
-        || mime.contains("officedocument")
-        || mime.starts_with("application/epub")
-        || mime == "application/json"
-    {
-        return is_doc_output(ext);
-    }
-    false
-}
-
-fn is_image_output(ext: &str) -> bool {
-    matches!(
-        ext,
-        "png"
-            | "jpg"
-            | "jpeg"
-            | "jpe"
-            | "jfif"
-            | "webp"
-            | "avif"
-            | "heic"
-            | "heif"
-            | "tiff"
-            | "tif"
-            | "gif"
-            | "jxl"
-            | "jp2"
-            | "j2k"
-            | "jpc"
-            | "jpt"
-            | "j2c"
-            | "hdr"
-            | "ppm"
-            | "pgm"
-            | "pbm"
-            | "pfm"
-            | "pnm"
-            | "fits"
-            | "fit"
-            | "fts"
-            | "bmp"
-            | "ico"
-            | "psd"
-            | "tga"
-            | "pcx"
-            | "pdf"
-            | "eps"
-            | "dds"
-    )
-}
-
-fn is_media_output(ext: &str) -> bool {
-    matches!(
-        ext,
-        "mp4"
-            | "mkv"
-            | "mov"
-            | "avi"
-            | "mp3"
-            | "wav"
-            | "flac"
-            | "ogg"
-            | "m4a"
-            | "aac"
-            | "webm"
-            | "opus"
-            | "m4v"
-            | "ts"
-            | "mts"
-            | "flv"
-            | "gif"
-            | "mpg"
-            | "mpeg"
-            | "vob"
-            | "ogv"
-            | "oga"
-            | "wv"
-            | "ac3"
-            | "dts"
-            | "aiff"
-            | "au"
-            | "amr"
-            | "3gp"
-            | "3g2"
-            | "mka"
-            | "mxf"
-            | "asf"
-            | "wmv"
-            | "rm"
-            | "rmvb"
-            | "adts"
-            | "spx"
-    )
-}
-
-fn is_doc_output(ext: &str) -> bool {
-    matches!(
-        ext,
-        "md" | "markdown"
-            | "txt"
-            | "html"
-            | "htm"
-            | "docx"
-            | "odt"
-            | "epub"
-            | "latex"
-            | "tex"
-            | "rst"
-            | "rtf"
-            | "org"
-            | "wiki"
-            | "textile"
-            | "fb2"
-            | "ipynb"
-            | "jira"
-            | "opml"
-            | "json"
-            | "typst"
-            | "djot"
-            | "man"
-            | "pdf"
-            | "pptx"
-            | "beamer"
-            | "icml"
-            | "tei"
-            | "texinfo"
-            | "context"
-            | "ms"
-            | "adoc"
-            | "asciidoc"
-    )
-}
-
-fn is_doc_folder_ext(ext: &str) -> bool {
-    matches!(
-        ext,
-        "md" | "txt"
-            | "html"
-            | "htm"
-            | "docx"
-            | "odt"
-            | "epub"
-            | "tex"
-            | "rst"
-            | "rtf"
-            | "org"
-            | "textile"
-            | "ipynb"
-            | "typst"
-    )
+fn prune_locks(locks: &mut HashMap<PathBuf, Instant>, ttl: Duration) {
+    locks.retain(|_, ts| ts.elapsed() < ttl);
 }
 
-fn pandoc_from_ext(ext: &str) -> &'static str {
-    match ext {
-        "html" | "htm" => "html",
-        "docx" => "docx",
-        "odt" => "odt",
-        "epub" => "epub",
-        "latex" | "tex" => "latex",
-        "rst" => "rst",
-        "rtf" => "rtf",
-        "org" => "org",
-        "wiki" => "mediawiki",
-        "textile" => "textile",
-        "fb2" => "fb2",
-        "ipynb" => "ipynb",
-        "jira" => "jira",
-        "opml" => "opml",
-        "json" => "json",
-        "typst" => "typst",
-        "djot" => "djot",
-        "csv" => "csv",
-        "tsv" => "tsv",
-        "t2t" => "t2t",
-        "creole" => "creole",
-        "twiki" => "twiki",
-        "man" | "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" => "man",
-        "xml" => "docbook",
-        _ => "markdown",
-    }
-}
-
-fn parse_trigger(raw_ext: &str) -> Option<Trigger> {
+/// Parses `!ext`, `!!ext`, optionally followed by `@opt,opt`, with several
+/// targets joined by `+` (`!webp@q80+avif`). A `?` after the bangs asks for
+/// a preview (`!?mp4`). Returns `Ok(None)` for names that are not triggers.
+fn parse_trigger(raw_ext: &str) -> Result<Option<Trigger>> {
     let lower = raw_ext.to_lowercase();
-    if lower.starts_with("!!") && lower.len() > 2 {
-        return Some(Trigger {
-            target_ext: lower.trim_start_matches("!!").to_string(),
-            destructive: true,
-        });
+    let (spec, destructive) = if let Some(rest) = lower.strip_prefix("!!") {
+        (rest, true)
+    } else if let Some(rest) = lower.strip_prefix('!') {
+        (rest, false)
+    } else {
+        return Ok(None);
+    };
+    let (spec, preview) = match spec.strip_prefix('?') {
+        Some(rest) => (rest, true),
+        None => (spec, false),
+    };
+    if spec.is_empty() {
+        return Ok(None);
     }
-    if lower.starts_with('!') && lower.len() > 1 {
-        return Some(Trigger {
-            target_ext: lower.trim_start_matches('!').to_string(),
-            destructive: false,
+
+    let mut targets: Vec<Target> = Vec::new();
+    for part in spec.split('+') {
+        let (ext, options) = match part.split_once('@') {
+            Some((ext, opts)) => (ext, ConvertOptions::parse(opts)?),
+            None => (part, ConvertOptions::default()),
+        };
+        if ext.is_empty() {
+            return Err(anyhow!("empty target in {raw_ext:?}"));
+        }
+        if targets.iter().any(|t| same_format(&t.ext, ext)) {
+            return Err(anyhow!("target {} is listed twice", ext.to_uppercase()));
+        }
+        targets.push(Target {
+            ext: ext.to_string(),
+            options,
         });
     }
-    None
-}
-
-fn version_dir_for_path(path: &Path, uid: u32) -> Result<PathBuf> {
-    let key = stable_path_key(path, uid);
-    let home_dir = home_dir_for_uid(uid)?;
-    Ok(home_dir.join(".local/share/morph-bang/versions").join(key))
-}
-
-fn ensure_version_paths_owned(version_dir: &Path, uid: u32, gid: u32) -> Result<()> {
-    let versions_root = version_dir
-        .parent()
-        .ok_or_else(|| anyhow!("invalid version directory"))?;
-    let app_root = versions_root
-        .parent()
-        .ok_or_else(|| anyhow!("invalid versions root"))?;
-
-    fs::create_dir_all(app_root).context("failed to create app version root")?;
-    fs::create_dir_all(versions_root).context("failed to create versions root")?;
-    fs::create_dir_all(version_dir).context("failed to create version directory")?;
-
-    chown_path(app_root, uid, gid)?;
-    chown_path(versions_root, uid, gid)?;
-    chown_path(version_dir, uid, gid)?;
-    Ok(())
+    let action = match targets.as_slice() {
+        [t] if preview && RESERVED_TARGETS.contains(&t.ext.as_str()) => {
+            return Err(anyhow!("{raw_ext:?}: built-in triggers have no preview"));
+        }
+        [t] if t.ext == "info" && t.options.is_empty() => Action::Info,
+        [t] if t.ext == "undo" && t.options.is_empty() => Action::Undo,
+        _ if targets
+            .iter()
+            .any(|t| RESERVED_TARGETS.contains(&t.ext.as_str())) =>
+        {
+            return Err(anyhow!(
+                "{raw_ext:?}: built-in triggers cannot take options or other targets"
+            ));
+        }
+        _ => Action::Convert,
+    };
+    Ok(Some(Trigger {
+        raw: raw_ext.to_string(),
+        action,
+        targets,
+        destructive,
+        preview,
+    }))
 }
 
 fn home_dir_for_uid(uid: u32) -> Result<PathBuf> {
@@ -916,88 +1855,495 @@ fn owner_from_home_path(path: &Path) -> Option<(u32, u32)> {
     Some((user.uid.as_raw(), user.gid.as_raw()))
 }
 
-fn store_version(
-    source_path: &Path,
-    version_dir: &Path,
-    source_ext: &str,
-    uid: u32,
-    gid: u32,
-) -> Result<()> {
-    let ext = sanitize_ext(if source_ext.is_empty() {
-        "bin"
-    } else {
-        source_ext
-    });
-    let version_file = next_version_path(version_dir, &ext)?;
-    fs::copy(source_path, &version_file)?;
-    chown_path(&version_file, uid, gid)?;
+/// Writes a report next to the triggered file, owned by `owner`. The
+/// directory is the user's, so the report replaces `sidecar` through a
+/// freshly created temporary file and never follows a symlink left there.
+fn write_sidecar(sidecar: &Path, body: &str, owner: Owner, mode: u32) -> Result<()> {
+    let (Some(parent), Some(name)) = (sidecar.parent(), sidecar.file_name()) else {
+        return Err(anyhow!("invalid report path {}", sidecar.display()));
+    };
+    let mut tmp = OsString::from(".");
+    tmp.push(name);
+    tmp.push(".tmp");
+    let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+    open(parent, flags, Mode::empty())
+        .map_err(Into::into)
+        .and_then(|dir| {
+            replace_file_at(
+                dir.as_fd(),
+                name,
+                &tmp,
+                body.as_bytes(),
+                owner.uid,
+                owner.gid,
+                mode,
+            )
+        })
+        .with_context(|| format!("failed to write {}", sidecar.display()))
+}
+
+fn chown_path(path: &Path, uid: u32, gid: u32) -> Result<()> {
+    chown(path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
+        .with_context(|| format!("failed to set ownership on {}", path.display()))?;
     Ok(())
 }
 
-fn next_version_path(version_dir: &Path, ext: &str) -> Result<PathBuf> {
-    let ts = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .context("clock error")?
-        .as_nanos();
-    let pid = std::process::id();
-    for seq in 0..1024u32 {
-        let candidate = version_dir.join(format!("{ts:020}-{pid:05}-{seq:04}.{ext}"));
-        if !candidate.exists() {
-            return Ok(candidate);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{config_with_store, fake_tool, TempDir};
+
+    fn trigger(raw: &str) -> Trigger {
+        parse_trigger(raw).unwrap().unwrap()
+    }
+
+    #[test]
+    fn ignores_names_that_are_not_triggers() {
+        for raw in ["", "jpg", "!", "!!", "!?"] {
+            assert!(parse_trigger(raw).unwrap().is_none(), "{raw} is a trigger");
         }
     }
-    Err(anyhow!(
-        "failed to allocate unique version filename in {}",
-        version_dir.display()
-    ))
-}
 
-fn sanitize_ext(ext: &str) -> String {
-    let sanitized: String = ext
-        .chars()
-        .map(|c| {
-            if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' {
-                c
-            } else {
-                '_'
-            }
-        })
-        .collect();
-    if sanitized.is_empty() {
-        "bin".to_string()
-    } else {
-        sanitized
+    #[test]
+    fn parses_a_single_target() {
+        let t = trigger("!WebP@q80");
+        assert_eq!(t.action, Action::Convert);
+        assert!(!t.destructive && !t.preview);
+        assert_eq!(t.targets.len(), 1);
+        assert_eq!(t.targets[0].ext, "webp");
+        assert_eq!(t.targets[0].options.quality, Some(80));
+        assert_eq!(t.raw, "!WebP@q80");
     }
-}
 
-fn stable_path_key(path: &Path, uid: u32) -> String {
-    let mut hasher = blake3::Hasher::new();
-    hasher.update(b"morph-bang:v1:path-key");
-    hasher.update(&uid.to_be_bytes());
-    hasher.update(&[0]);
-    hasher.update(path.as_os_str().as_bytes());
-    hasher.finalize().to_hex().to_string()
-}
+    #[test]
+    fn parses_several_targets() {
+        let t = trigger("!!webp@q80+avif");
+        assert!(t.destructive);
+        let targets: Vec<String> = t.targets.iter().map(Target::to_string).collect();
+        assert_eq!(targets, ["webp@q80", "avif"]);
 
-fn chown_path(path: &Path, uid: u32, gid: u32) -> Result<()> {
-    chown(path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
-        .with_context(|| format!("failed to set ownership on {}", path.display()))?;
-    Ok(())
-}
+        assert!(parse_trigger("!webp+").is_err());
+        assert!(parse_trigger("!webp+webp").is_err());
+    }
 
-fn find_latest_version_by_ext(version_dir: &Path, target_ext: &str) -> Option<PathBuf> {
-    let mut matches: Vec<PathBuf> = fs::read_dir(version_dir)
-        .ok()?
-        .filter_map(|e| e.ok())
-        .map(|e| e.path())
-        .filter(|p| p.is_file())
-        .filter(|p| {
-            p.extension()
-                .and_then(|e| e.to_str())
-                .map(|e| e.eq_ignore_ascii_case(target_ext))
-                .unwrap_or(false)
-        })
-        .collect();
-    matches.sort();
-    matches.pop()
+    #[test]
+    fn rejects_targets_that_name_one_format_twice() {
+        assert!(parse_trigger("!jpg+jpeg").is_err());
+        assert!(parse_trigger("!TIF+tiff").is_err());
+    }
+
+    #[test]
+    fn parses_previews() {
+        let t = trigger("!?mp4");
+        assert!(t.preview && !t.destructive);
+        assert_eq!(t.targets[0].ext, "mp4");
+
+        let t = trigger("!!?png+pdf");
+        assert!(t.preview && t.destructive);
+        assert_eq!(t.targets.len(), 2);
+        assert!(parse_trigger("!?info").is_err());
+        assert!(parse_trigger("!?undo").is_err());
+    }
+
+    #[test]
+    fn parses_the_info_action() {
+        assert_eq!(trigger("!info").action, Action::Info);
+        assert_eq!(trigger("!INFO").action, Action::Info);
+        assert!(parse_trigger("!info@q80").is_err());
+        assert!(parse_trigger("!png+info").is_err());
+    }
+
+    #[test]
+    fn parses_the_undo_action() {
+        let t = trigger("!undo");
+        assert_eq!(t.action, Action::Undo);
+        assert!(!t.destructive);
+        assert!(parse_trigger("!undo@q80").is_err());
+        assert!(parse_trigger("!png+undo").is_err());
+    }
+
+    #[test]
+    fn writes_sidecars_without_following_symlinks() {
+        let dir = TempDir::new("sidecar");
+        let outside = TempDir::new("sidecar-outside");
+        let target = outside.join("shadow");
+        fs::write(&target, "secret").unwrap();
+        let sidecar = dir.join("photo.info.json");
+        std::os::unix::fs::symlink(&target, &sidecar).unwrap();
+        std::os::unix::fs::symlink(&target, dir.join(".photo.info.json.tmp")).unwrap();
+        let owner = Owner {
+            uid: Uid::current().as_raw(),
+            gid: Gid::current().as_raw(),
+            mode: 0o755,
+        };
+
+        write_sidecar(&sidecar, "{}\n", owner, 0o644).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "secret");
+        let meta = fs::symlink_metadata(&sidecar).unwrap();
+        assert!(meta.is_file());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o644);
+        assert_eq!(fs::read_to_string(&sidecar).unwrap(), "{}\n");
+    }
+
+    #[test]
+    fn previews_without_converting_or_touching_history() {
+        let root = TempDir::new("preview");
+        let store = root.join("store");
+        let cfg = config_with_store(&store);
+        let owner = Owner {
+            uid: Uid::current().as_raw(),
+            gid: Gid::current().as_raw(),
+            mode: 0o644,
+        };
+        let mut settings = cfg.settings(None);
+        settings.notifications = Verbosity::None;
+        let photo = root.join("photo.png");
+        let path = root.join("photo.!?webp+avif");
+        fs::write(&path, "pixels").unwrap();
+        let version_dir = version_dir_for_path(&cfg, &path, owner.uid).unwrap();
+        let source = Source {
+            mime: "image/png".to_string(),
+            ext: "png".to_string(),
+        };
+
+        for sidecar in [false, true] {
+            settings.preview_sidecar = sidecar;
+            let job = Job {
+                cfg: &cfg,
+                settings: settings.clone(),
+                path: &path,
+                filename: "photo.!?webp+avif",
+                origin: Some(&photo),
+                owner,
+            };
+            handle_preview(&job, &trigger("!?webp+avif"), &source, &version_dir).unwrap();
+            assert_eq!(fs::read_to_string(&photo).unwrap(), "pixels");
+            fs::rename(&photo, &path).unwrap();
+        }
+
+        let mut left: Vec<String> = fs::read_dir(&*root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["photo.!?webp+avif", "photo.preview.txt"]);
+        assert!(!store.exists());
+        let report = fs::read_to_string(root.join("photo.preview.txt")).unwrap();
+        assert!(report.contains("WEBP: vips"), "{report}");
+        assert!(report.contains("AVIF: vips"), "{report}");
+    }
+
+    #[test]
+    fn writes_the_info_report_beside_the_file_without_execute_bits() {
+        let root = TempDir::new("info");
+        let mut cfg = config_with_store(&root.join("store"));
+        cfg.engines.file = fake_tool(
+            &root,
+            "file",
+            r#"case "$1" in --mime-type) echo application/pdf ;; *) echo pdf ;; esac"#,
+        );
+        cfg.engines.pdfinfo = fake_tool(&root, "pdfinfo", "echo 'Pages: 4'");
+        let owner = Owner {
+            uid: Uid::current().as_raw(),
+            gid: Gid::current().as_raw(),
+            mode: 0o755,
+        };
+        let mut settings = cfg.settings(None);
+        settings.notifications = Verbosity::None;
+        let doc = root.join("doc.pdf");
+        let path = root.join("doc.!info");
+        fs::write(&path, "%PDF-1.7").unwrap();
+        let job = Job {
+            cfg: &cfg,
+            settings,
+            path: &path,
+            filename: "doc.!info",
+            origin: Some(&doc),
+            owner,
+        };
+
+        handle_info(&job).unwrap();
+        assert_eq!(fs::read_to_string(&doc).unwrap(), "%PDF-1.7");
+        let sidecar = root.join("doc.info.json");
+        let mode = fs::metadata(&sidecar).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&sidecar).unwrap()).unwrap();
+        assert_eq!(report["file"], "doc.pdf");
+        assert_eq!(report["mime"], "application/pdf");
+        assert_eq!(report["extension"], "pdf");
+        assert_eq!(report["size"], 8);
+        assert_eq!(report["pdf_pages"], 4);
+        assert!(report.get("media").is_none());
+    }
+
+    fn settings_with_presets(presets: &[(&str, &str, &str)]) -> Settings {
+        let mut settings = Config::default().settings(None);
+        for (name, target, options) in presets {
+            let preset = config::Preset {
+                target: target.to_string(),
+                options: options.to_string(),
+            };
+            settings.presets.insert(name.to_string(), preset);
+        }
+        settings
+    }
+
+    #[test]
+    fn expands_presets_with_trigger_options_winning() {
+        let settings = settings_with_presets(&[("web", "webp", "q80,w1920")]);
+        let resolved = resolve_presets(trigger("!web@q60+png"), &settings).unwrap();
+        let targets: Vec<String> = resolved.targets.iter().map(Target::to_string).collect();
+        assert_eq!(targets, ["webp@q60,w1920", "png"]);
+    }
+
+    #[test]
+    fn user_presets_override_system_ones() {
+        let mut cfg = Config::default();
+        cfg.presets.insert(
+            "web".to_string(),
+            config::Preset {
+                target: "webp".to_string(),
+                options: "q80".to_string(),
+            },
+        );
+        let mut user = UserConfig::default();
+        user.presets.insert(
+            "web".to_string(),
+            config::Preset {
+                target: "avif".to_string(),
+                options: String::new(),
+            },
+        );
+        let resolved = resolve_presets(trigger("!web"), &cfg.settings(Some(&user))).unwrap();
+        assert_eq!(resolved.targets[0].to_string(), "avif");
+        let resolved = resolve_presets(trigger("!web"), &cfg.settings(None)).unwrap();
+        assert_eq!(resolved.targets[0].to_string(), "webp@q80");
+    }
+
+    #[test]
+    fn rejects_presets_that_expand_to_the_same_format_twice() {
+        let settings = settings_with_presets(&[("web", "webp", "q80"), ("small", "webp", "w640")]);
+        assert!(resolve_presets(trigger("!web+webp"), &settings).is_err());
+        assert!(resolve_presets(trigger("!web+small"), &settings).is_err());
+        assert!(resolve_presets(trigger("!web+avif"), &settings).is_ok());
+    }
+
+    #[test]
+    fn reports_a_preset_with_invalid_options() {
+        let settings = settings_with_presets(&[("web", "webp", "q80,z1")]);
+        let err = resolve_presets(trigger("!web"), &settings).unwrap_err();
+        assert!(format!("{err:#}").contains("\"web\""), "{err:#}");
+    }
+
+    fn version_origin<'a>(name: &'a str, ext: &'a str) -> Origin<'a> {
+        Origin {
+            name,
+            mime: "image/jpeg",
+            ext,
+            trigger: "!png",
+            targets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn undoes_to_the_recorded_name_and_falls_back_to_the_stored_extension() {
+        let root = TempDir::new("undo-destination");
+        let version_dir = root.join("a".repeat(64));
+        fs::create_dir(&version_dir).unwrap();
+        let original = root.join("photo.jpeg");
+        fs::write(&original, "jpeg").unwrap();
+        let (uid, gid) = (Uid::current().as_raw(), Gid::current().as_raw());
+        let cfg = Config::default();
+        let version = store_version(
+            &cfg,
+            &original,
+            &version_dir,
+            &version_origin("photo.jpeg", "jpg"),
+            uid,
+            gid,
+        )
+        .unwrap();
+        let trigger_path = root.join("photo.!undo");
+        assert_eq!(
+            undo_destination(&trigger_path, &version_dir, &version),
+            original
+        );
+
+        // Names that would leave the folder or fire again are not used.
+        for name in ["../photo.jpeg", "photo.!jpg", ""] {
+            let mut manifest = Manifest::load(&version_dir).unwrap();
+            manifest.versions[0].original_name = name.to_string();
+            manifest.save(&version_dir, uid, gid).unwrap();
+            assert_eq!(
+                undo_destination(&trigger_path, &version_dir, &version),
+                root.join("photo.jpg")
+            );
+        }
+    }
+
+    #[test]
+    fn undo_restores_the_newest_version_that_differs_and_keeps_the_current_one() {
+        let root = TempDir::new("undo-restore");
+        let cfg = config_with_store(&root.join("store"));
+        let owner = Owner {
+            uid: Uid::current().as_raw(),
+            gid: Gid::current().as_raw(),
+            mode: 0o644,
+        };
+        let mut settings = cfg.settings(None);
+        settings.notifications = Verbosity::None;
+        let photo = root.join("photo.png");
+        let version_dir = version_dir_for_path(&cfg, &photo, owner.uid).unwrap();
+        ensure_version_paths_owned(&version_dir, owner.uid, owner.gid).unwrap();
+        let origin = version_origin("photo.png", "png");
+        for contents in ["first", "second", "third"] {
+            fs::write(&photo, contents).unwrap();
+            store_version(&cfg, &photo, &version_dir, &origin, owner.uid, owner.gid).unwrap();
+        }
+        let source = Source {
+            mime: "image/png".to_string(),
+            ext: "png".to_string(),
+        };
+        let path = root.join("photo.!undo");
+        let job = Job {
+            cfg: &cfg,
+            settings,
+            path: &path,
+            filename: "photo.!undo",
+            origin: Some(&photo),
+            owner,
+        };
+
+        // The newest version holds what the file already has, so undo goes
+        // one further back.
+        fs::rename(&photo, &path).unwrap();
+        handle_undo(&job, &trigger("!undo"), &source, &version_dir).unwrap();
+        assert_eq!(fs::read_to_string(&photo).unwrap(), "second");
+        assert!(!path.exists());
+        let versions = store::list_versions(&version_dir);
+        assert_eq!(versions.len(), 4);
+        assert_eq!(fs::read_to_string(&versions[3]).unwrap(), "third");
+
+        // Without a differing version the file only gets its name back.
+        let empty = TempDir::new("undo-empty");
+        fs::rename(&photo, &path).unwrap();
+        assert!(handle_undo(&job, &trigger("!undo"), &source, &empty).is_err());
+        assert_eq!(fs::read_to_string(&photo).unwrap(), "second");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn undo_refuses_to_replace_a_file_and_swaps_back_when_repeated() {
+        let root = TempDir::new("undo-swap");
+        let version_dir = root.join("a".repeat(64));
+        fs::create_dir(&version_dir).unwrap();
+        let owner = Owner {
+            uid: Uid::current().as_raw(),
+            gid: Gid::current().as_raw(),
+            mode: 0o644,
+        };
+        let cfg = Config::default();
+        let mut settings = cfg.settings(None);
+        settings.notifications = Verbosity::None;
+        let (jpeg, png) = (root.join("photo.jpeg"), root.join("photo.png"));
+        fs::write(&jpeg, "jpeg").unwrap();
+        let origin = version_origin("photo.jpeg", "jpg");
+        store_version(&cfg, &jpeg, &version_dir, &origin, owner.uid, owner.gid).unwrap();
+        fs::rename(&jpeg, &png).unwrap();
+        fs::write(&png, "png").unwrap();
+
+        let undo = |from: &Path, mime: &str, ext: &str| {
+            let path = root.join("photo.!undo");
+            fs::rename(from, &path).unwrap();
+            let job = Job {
+                cfg: &cfg,
+                settings: settings.clone(),
+                path: &path,
+                filename: "photo.!undo",
+                origin: Some(from),
+                owner,
+            };
+            let source = Source {
+                mime: mime.to_string(),
+                ext: ext.to_string(),
+            };
+            handle_undo(&job, &trigger("!undo"), &source, &version_dir)
+        };
+
+        fs::write(&jpeg, "in the way").unwrap();
+        assert!(undo(&png, "image/png", "png").is_err());
+        assert_eq!(fs::read_to_string(&png).unwrap(), "png");
+        assert_eq!(fs::read_to_string(&jpeg).unwrap(), "in the way");
+        fs::remove_file(&jpeg).unwrap();
+
+        undo(&png, "image/png", "png").unwrap();
+        assert_eq!(fs::read_to_string(&jpeg).unwrap(), "jpeg");
+        assert!(!png.exists());
+        undo(&jpeg, "image/jpeg", "jpg").unwrap();
+        assert_eq!(fs::read_to_string(&png).unwrap(), "png");
+        assert!(!jpeg.exists());
+    }
+
+    #[test]
+    fn restores_the_stored_pdf_only_from_unchanged_pages() {
+        let root = TempDir::new("pages-to-pdf");
+        let version_dir = root.join("a".repeat(64));
+        fs::create_dir(&version_dir).unwrap();
+        let owner = Owner {
+            uid: Uid::current().as_raw(),
+            gid: Gid::current().as_raw(),
+            mode: 0o644,
+        };
+        let cfg = Config::default();
+        let mut settings = cfg.settings(None);
+        settings.notifications = Verbosity::None;
+        let (pdf, pages) = (root.join("doc.pdf"), root.join("doc"));
+        fs::write(&pdf, "%PDF original").unwrap();
+        let origin = Origin {
+            name: "doc.pdf",
+            mime: "application/pdf",
+            ext: "pdf",
+            trigger: "!png",
+            targets: Vec::new(),
+        };
+        store_version(&cfg, &pdf, &version_dir, &origin, owner.uid, owner.gid).unwrap();
+        fs::remove_file(&pdf).unwrap();
+        fs::create_dir(&pages).unwrap();
+        fs::write(pages.join("doc-1.png"), "page one").unwrap();
+        fs::write(pages.join("doc-2.png"), "page two").unwrap();
+        let pdf_hash = blake3::hash(b"%PDF original").to_hex().to_string();
+        let pages_hash = hash_path(&pages).unwrap().to_hex().to_string();
+        record_conversion(
+            &version_dir,
+            &pdf_hash,
+            &pages_hash,
+            "",
+            owner.uid,
+            owner.gid,
+        )
+        .unwrap();
+
+        let path = root.join("doc.!pdf");
+        fs::rename(&pages, &path).unwrap();
+        let job = Job {
+            cfg: &cfg,
+            settings,
+            path: &path,
+            filename: "doc.!pdf",
+            origin: Some(&pages),
+            owner,
+        };
+        handle_directory_trigger(&job, &trigger("!pdf"), &version_dir).unwrap();
+        assert_eq!(fs::read_to_string(&pdf).unwrap(), "%PDF original");
+        assert!(pages.is_dir() && !path.exists());
+
+        // An edited page no longer matches, so the PDF is built afresh.
+        let target = &trigger("!pdf").targets[0];
+        let unchanged = hash_path(&pages).unwrap().to_hex().to_string();
+        assert!(history_match(&version_dir, target, &unchanged).is_some());
+        fs::write(pages.join("doc-2.png"), "page two, annotated").unwrap();
+        let edited = hash_path(&pages).unwrap().to_hex().to_string();
+        assert!(history_match(&version_dir, target, &edited).is_none());
+    }
 }
@@ -0,0 +1,295 @@
+use crate::config::QualityConfig;
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+/// Conversion settings requested after the `@` of a trigger, e.g. the
+/// `q80,w1920` in `photo.!jpg@q80,w1920`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConvertOptions {
+    /// Lossy image quality, 1-100 (`q80`).
+    pub quality: Option<u8>,
+    /// Maximum output width in pixels (`w1920`).
+    pub max_width: Option<u32>,
+    /// Maximum output height in pixels (`h1080`).
+    pub max_height: Option<u32>,
+    /// Audio bitrate (`192k`).
+    pub audio_bitrate: Option<String>,
+    /// Video constant rate factor, 0-51 (`crf23`).
+    pub video_crf: Option<u8>,
+    /// Inclusive, 1-based PDF page range (`p2` or `p2-5`).
+    pub pages: Option<(u32, u32)>,
+    /// Set when the trigger asked for encoder settings, which rules out a
+    /// stream-copy remux. Defaults from config never set it.
+    pub reencode: bool,
+}
+
+impl ConvertOptions {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut opts = Self::default();
+        for token in spec.split(',') {
+            opts.apply_token(token)?;
+        }
+        opts.reencode = opts.max_width.is_some()
+            || opts.max_height.is_some()
+            || opts.audio_bitrate.is_some()
+            || opts.video_crf.is_some();
+        Ok(opts)
+    }
+
+    fn apply_token(&mut self, token: &str) -> Result<()> {
+        if token.is_empty() {
+            return Err(anyhow!("empty conversion option"));
+        }
+        if let Some(v) = token.strip_prefix("crf") {
+            let crf = parse_number(token, v)?;
+            if crf > 51 {
+                return Err(anyhow!("option {token:?}: crf must be between 0 and 51"));
+            }
+            return set_once(&mut self.video_crf, crf as u8, token);
+        }
+        if let Some(v) = token.strip_suffix('k') {
+            let kbps = parse_number(token, v)?;
+            if kbps == 0 {
+                return Err(anyhow!("option {token:?}: bitrate must be positive"));
+            }
+            return set_once(&mut self.audio_bitrate, format!("{kbps}k"), token);
+        }
+        let mut chars = token.chars();
+        let key = chars.next();
+        let value = chars.as_str();
+        match key {
+            Some('q') => {
+                let q = parse_number(token, value)?;
+                if !(1..=100).contains(&q) {
+                    return Err(anyhow!(
+                        "option {token:?}: quality must be between 1 and 100"
+                    ));
+                }
+                set_once(&mut self.quality, q as u8, token)
+            }
+            Some('w') => set_once(&mut self.max_width, parse_dimension(token, value)?, token),
+            Some('h') => set_once(&mut self.max_height, parse_dimension(token, value)?, token),
+            Some('p') => {
+                let (first, last) = match value.split_once('-') {
+                    Some((a, b)) => (parse_number(token, a)?, parse_number(token, b)?),
+                    None => {
+                        let page = parse_number(token, value)?;
+                        (page, page)
+                    }
+                };
+                if first == 0 || last < first {
+                    return Err(anyhow!("option {token:?}: invalid page range"));
+                }
+                set_once(&mut self.pages, (first, last), token)
+            }
+            _ => Err(anyhow!("unknown conversion option {token:?}")),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Fills settings the trigger left unset from the user's quality defaults.
+    pub fn with_defaults(mut self, quality: &QualityConfig) -> Self {
+        if self.quality.is_none() {
+            self.quality = quality.image;
+        }
+        if self.audio_bitrate.is_none() {
+            self.audio_bitrate = quality.audio_bitrate.clone();
+        }
+        if self.video_crf.is_none() {
+            self.video_crf = quality.video_crf;
+        }
+        self
+    }
+
+    /// Returns `self` with every option set in `other` taking precedence.
+    pub fn overridden_by(mut self, other: &ConvertOptions) -> Self {
+        if other.quality.is_some() {
+            self.quality = other.quality;
+        }
+        if other.max_width.is_some() {
+            self.max_width = other.max_width;
+        }
+        if other.max_height.is_some() {
+            self.max_height = other.max_height;
+        }
+        if other.audio_bitrate.is_some() {
+            self.audio_bitrate = other.audio_bitrate.clone();
+        }
+        if other.video_crf.is_some() {
+            self.video_crf = other.video_crf;
+        }
+        if other.pages.is_some() {
+            self.pages = other.pages;
+        }
+        self.reencode |= other.reencode;
+        self
+    }
+
+    pub fn wants_resize(&self) -> bool {
+        self.max_width.is_some() || self.max_height.is_some()
+    }
+
+    /// The options that decide what an output holds rather than how it is
+    /// encoded: its pages and size.
+    pub fn content(&self) -> Self {
+        Self {
+            max_width: self.max_width,
+            max_height: self.max_height,
+            pages: self.pages,
+            reencode: self.wants_resize(),
+            ..Self::default()
+        }
+    }
+
+    /// The content options of one conversion that gives what converting
+    /// with `self` and then with `next` gave. Sizes are upper bounds, so the
+    /// smaller one holds; two page selections cannot be combined.
+    pub fn then(&self, next: &Self) -> Option<Self> {
+        if self.pages.is_some() && next.pages.is_some() {
+            return None;
+        }
+        let smaller = |a: Option<u32>, b: Option<u32>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Some(
+            Self {
+                max_width: smaller(self.max_width, next.max_width),
+                max_height: smaller(self.max_height, next.max_height),
+                pages: self.pages.or(next.pages),
+                ..Self::default()
+            }
+            .content(),
+        )
+    }
+}
+
+impl fmt::Display for ConvertOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(q) = self.quality {
+            parts.push(format!("q{q}"));
+        }
+        if let Some(w) = self.max_width {
+            parts.push(format!("w{w}"));
+        }
+        if let Some(h) = self.max_height {
+            parts.push(format!("h{h}"));
+        }
+        if let Some(bitrate) = &self.audio_bitrate {
+            parts.push(bitrate.clone());
+        }
+        if let Some(crf) = self.video_crf {
+            parts.push(format!("crf{crf}"));
+        }
+        match self.pages {
+            Some((a, b)) if a == b => parts.push(format!("p{a}")),
+            Some((a, b)) => parts.push(format!("p{a}-{b}")),
+            None => {}
+        }
+        f.write_str(&parts.join(","))
+    }
+}
+
+fn parse_number(token: &str, digits: &str) -> Result<u32> {
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(anyhow!("option {token:?}: expected a number"));
+    }
+    digits
+        .parse()
+        .map_err(|_| anyhow!("option {token:?}: number out of range"))
+}
+
+fn parse_dimension(token: &str, digits: &str) -> Result<u32> {
+    match parse_number(token, digits)? {
+        0 => Err(anyhow!("option {token:?}: size must be positive")),
+        v => Ok(v),
+    }
+}
+
+fn set_once<T>(slot: &mut Option<T>, value: T, token: &str) -> Result<()> {
+    if slot.is_some() {
+        return Err(anyhow!("option {token:?} conflicts with an earlier option"));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quality_and_size() {
+        let opts = ConvertOptions::parse("q80,w1920").unwrap();
+        assert_eq!(opts.quality, Some(80));
+        assert_eq!(opts.max_width, Some(1920));
+        assert_eq!(opts.max_height, None);
+        assert!(opts.reencode);
+        assert_eq!(opts.to_string(), "q80,w1920");
+    }
+
+    #[test]
+    fn parses_page_ranges() {
+        let range = ConvertOptions::parse("p2-4").unwrap();
+        assert_eq!(range.pages, Some((2, 4)));
+        assert!(!range.reencode);
+        assert_eq!(range.to_string(), "p2-4");
+
+        let single = ConvertOptions::parse("p3").unwrap();
+        assert_eq!(single.pages, Some((3, 3)));
+        assert_eq!(single.to_string(), "p3");
+
+        assert!(ConvertOptions::parse("p0").is_err());
+        assert!(ConvertOptions::parse("p4-2").is_err());
+    }
+
+    #[test]
+    fn parses_media_options() {
+        let opts = ConvertOptions::parse("192k,crf23").unwrap();
+        assert_eq!(opts.audio_bitrate.as_deref(), Some("192k"));
+        assert_eq!(opts.video_crf, Some(23));
+        assert!(opts.reencode);
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_options() {
+        for spec in [
+            "z9", "q", "q0", "q101", "w0", "crf52", "0k", "q80,", "q80,q90",
+        ] {
+            assert!(ConvertOptions::parse(spec).is_err(), "{spec} was accepted");
+        }
+    }
+
+    #[test]
+    fn keeps_only_what_decides_the_content() {
+        let opts = ConvertOptions::parse("q80,p2,192k").unwrap();
+        assert_eq!(opts.content().to_string(), "p2");
+        assert!(!opts.content().reencode);
+        assert!(
+            ConvertOptions::parse("q80,w800")
+                .unwrap()
+                .content()
+                .reencode
+        );
+    }
+
+    #[test]
+    fn composes_successive_conversions() {
+        let first = ConvertOptions::parse("p2,w1000").unwrap();
+        let next = ConvertOptions::parse("q50,w800,h600").unwrap();
+        let both = first.then(&next).unwrap();
+        assert_eq!(both.to_string(), "w800,h600,p2");
+        assert!(both.reencode);
+
+        let pages = ConvertOptions::parse("p3").unwrap();
+        assert!(first.then(&pages).is_none());
+        assert_eq!(
+            ConvertOptions::default().then(&pages),
+            Some(pages.content())
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use crate::engine::{copy_owner_and_perms, create_workspace, engine_for, morph_engine, Engine};
+use crate::formats::{
+    is_doc_output, is_frame_output, is_image_output, is_valid_target, same_format,
+};
+use crate::options::ConvertOptions;
+use crate::Job;
+use anyhow::{anyhow, Context, Result};
+use nix::errno::Errno;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Formats a conversion may pass through when no engine produces the target
+/// directly, in order of preference, with the MIME type each is read back as.
+const INTERMEDIATES: &[(&str, &str)] = &[("pdf", "application/pdf"), ("png", "image/png")];
+
+/// Longest chain the planner considers, counting the final step.
+const MAX_STEPS: usize = 3;
+
+/// One engine invocation in a conversion plan.
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub engine: Engine,
+    pub from_mime: String,
+    pub from_ext: String,
+    pub to_ext: String,
+}
+
+impl Step {
+    fn new(engine: Engine, from_mime: &str, from_ext: &str, to_ext: &str) -> Self {
+        Self {
+            engine,
+            from_mime: from_mime.to_string(),
+            from_ext: from_ext.to_string(),
+            to_ext: to_ext.to_string(),
+        }
+    }
+}
+
+/// Finds the steps that turn a `mime` file into `target_ext`: a single step
+/// when an engine handles the pair, otherwise the shortest chain through
+/// [`INTERMEDIATES`], e.g. pandoc → PDF → vips or ffmpeg → PNG → vips.
+pub fn plan(mime: &str, source_ext: &str, target_ext: &str) -> Option<Vec<Step>> {
+    if let Some(engine) = direct_engine(mime, target_ext) {
+        return Some(vec![Step::new(engine, mime, source_ext, target_ext)]);
+    }
+
+    let mut queue = VecDeque::from([(Vec::<Step>::new(), mime, source_ext)]);
+    while let Some((steps, mime, ext)) = queue.pop_front() {
+        if steps.len() + 2 > MAX_STEPS {
+            continue;
+        }
+        for &(via_ext, via_mime) in INTERMEDIATES {
+            if same_format(via_ext, ext) || steps.iter().any(|s| s.to_ext == via_ext) {
+                continue;
+            }
+            let Some(engine) = intermediate_engine(mime, ext, via_ext) else {
+                continue;
+            };
+            let mut next = steps.clone();
+            next.push(Step::new(engine, mime, ext, via_ext));
+            if let Some(engine) = capable_engine(via_mime, target_ext) {
+                next.push(Step::new(engine, via_mime, via_ext, target_ext));
+                return Some(next);
+            }
+            queue.push_back((next, via_mime, via_ext));
+        }
+    }
+    None
+}
+
+/// The formats a chained plan passes through, e.g. `PDF` or `PDF → PNG`.
+pub fn via(steps: &[Step]) -> Option<String> {
+    let (_, intermediates) = steps.split_last()?;
+    if intermediates.is_empty() {
+        return None;
+    }
+    Some(
+        intermediates
+            .iter()
+            .map(|s| s.to_ext.to_uppercase())
+            .collect::<Vec<_>>()
+            .join(" → "),
+    )
+}
+
+/// The engine for a one-step conversion. This keeps the lenient pairing
+/// `is_valid_target` has always allowed, except that video frames only go
+/// straight to formats ffmpeg can encode itself.
+fn direct_engine(mime: &str, target_ext: &str) -> Option<Engine> {
+    if !is_valid_target(mime, target_ext) {
+        return None;
+    }
+    let engine = engine_for(mime, target_ext)?;
+    if engine == Engine::Media && is_image_output(target_ext) && !is_frame_output(target_ext) {
+        return None;
+    }
+    Some(engine)
+}
+
+/// Like [`direct_engine`], but only when the engine can actually write the
+/// target. Chained steps use this so a plan never hinges on a pairing that
+/// is accepted but cannot succeed, such as vips writing a document.
+fn capable_engine(mime: &str, target_ext: &str) -> Option<Engine> {
+    let engine = direct_engine(mime, target_ext)?;
+    let writable = match engine {
+        Engine::Image => is_image_output(target_ext),
+        Engine::Media => true,
+        Engine::Document => is_doc_output(target_ext),
+    };
+    writable.then_some(engine)
+}
+
+/// The engine for a step that feeds another one. Rasterising a PDF may
+/// expand it into a folder of pages, which only the final step can do.
+fn intermediate_engine(mime: &str, ext: &str, via_ext: &str) -> Option<Engine> {
+    let engine = capable_engine(mime, via_ext)?;
+    if engine == Engine::Image && ext == "pdf" {
+        return None;
+    }
+    Some(engine)
+}
+
+/// Runs a chained plan on `input`, usually `job.path`, writing the final
+/// output to `out`. Intermediate files live in a private workspace that is
+/// removed afterwards. Returns the final engine status; 2 means its pages
+/// were moved into a folder next to the source.
+pub fn run(
+    job: &Job,
+    input: &Path,
+    steps: &[Step],
+    out: &Path,
+    opts: &ConvertOptions,
+) -> Result<i32> {
+    let owner = job.owner;
+    let workspace = create_workspace("chain", job.path, owner.uid, owner.gid)?;
+    let result = run_steps(job, input, steps, out, opts, &workspace);
+    let _ = fs::remove_dir_all(&workspace);
+    result
+}
+
+fn run_steps(
+    job: &Job,
+    input: &Path,
+    steps: &[Step],
+    out: &Path,
+    opts: &ConvertOptions,
+    workspace: &Path,
+) -> Result<i32> {
+    let mut input = input.to_path_buf();
+    for (idx, step) in steps.iter().enumerate() {
+        let last = idx + 1 == steps.len();
+        let label = step.to_ext.to_uppercase();
+        let step_out = if last {
+            out.to_path_buf()
+        } else {
+            workspace.join(format!("step{}.{}", idx + 1, step.to_ext))
+        };
+        // Requested options describe the final output only.
+        let step_opts = if last {
+            opts.clone()
+        } else {
+            ConvertOptions::default()
+        };
+        let status = morph_engine(
+            job.cfg,
+            &input,
+            &step_out,
+            &step.to_ext,
+            &step.from_ext,
+            &step.from_mime,
+            &step_opts,
+        )
+        .with_context(|| format!("{label} step failed"))?;
+        if status == 2 {
+            if !last {
+                return Err(anyhow!("{label} step produced several pages"));
+            }
+            move_folder(&input.with_extension(""), &job.path.with_extension(""))?;
+            return Ok(2);
+        }
+        if !last {
+            copy_owner_and_perms(job.path, &step_out)?;
+        }
+        input = step_out;
+    }
+    Ok(0)
+}
+
+/// Moves a folder of pages out of the workspace, copying when the temp dir
+/// is on another filesystem.
+pub fn move_folder(from: &Path, to: &Path) -> Result<()> {
+    if to.exists() {
+        return Err(anyhow!("{} already exists", to.display()));
+    }
+    match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.raw_os_error() == Some(Errno::EXDEV as i32) => {}
+        Err(err) => return Err(err).with_context(|| format!("failed to create {}", to.display())),
+    }
+    fs::create_dir(to)?;
+    copy_owner_and_perms(from, to)?;
+    let mut pages: Vec<PathBuf> = fs::read_dir(from)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    pages.sort();
+    for page in pages {
+        let Some(name) = page.file_name() else {
+            continue;
+        };
+        let dest = to.join(name);
+        fs::copy(&page, &dest)?;
+        copy_owner_and_perms(&page, &dest)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A plan as `engine:from>to` per step.
+    fn steps(mime: &str, source_ext: &str, target_ext: &str) -> Option<Vec<String>> {
+        let steps = plan(mime, source_ext, target_ext)?;
+        Some(
+            steps
+                .iter()
+                .map(|s| format!("{:?}:{}>{}", s.engine, s.from_ext, s.to_ext))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn converts_directly_when_an_engine_can() {
+        assert_eq!(
+            steps("image/png", "png", "webp").unwrap(),
+            ["Image:png>webp"]
+        );
+        assert_eq!(steps("video/mp4", "mp4", "mkv").unwrap(), ["Media:mp4>mkv"]);
+        assert_eq!(steps("video/mp4", "mp4", "png").unwrap(), ["Media:mp4>png"]);
+    }
+
+    #[test]
+    fn chains_through_intermediates() {
+        assert_eq!(
+            steps("text/markdown", "md", "png").unwrap(),
+            ["Document:md>pdf", "Image:pdf>png"]
+        );
+        assert_eq!(
+            steps("video/mp4", "mp4", "avif").unwrap(),
+            ["Media:mp4>png", "Image:png>avif"]
+        );
+    }
+
+    #[test]
+    fn finds_no_plan_for_unrelated_formats() {
+        assert!(plan("audio/mpeg", "mp3", "png").is_none());
+        assert!(plan("application/octet-stream", "bin", "webp").is_none());
+    }
+
+    #[test]
+    fn names_the_formats_a_chain_passes_through() {
+        let chained = plan("text/markdown", "md", "png").unwrap();
+        assert_eq!(via(&chained).as_deref(), Some("PDF"));
+        let direct = plan("image/png", "png", "webp").unwrap();
+        assert_eq!(via(&direct), None);
+    }
+}
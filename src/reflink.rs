@@ -0,0 +1,94 @@
+use nix::libc;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// `_IOWR('f', 11, struct fiemap)`; libc does not export it.
+const FS_IOC_FIEMAP: libc::Ioctl = 0xC020_660B_u32 as libc::Ioctl;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+const EXTENTS_PER_CALL: usize = 32;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; EXTENTS_PER_CALL],
+}
+
+/// Copies `from` to `to` like `fs::copy`. With `reflink`, the copy first
+/// tries to share `from`'s extents (btrfs, XFS), which is instant and takes
+/// no space until either file changes; filesystems without reflinks, or a
+/// copy across filesystems, fall back to copying the bytes.
+pub fn copy_file(from: &Path, to: &Path, reflink: bool) -> io::Result<()> {
+    if reflink && clone_file(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to).map(|_| ())
+}
+
+fn clone_file(from: &Path, to: &Path) -> io::Result<()> {
+    let src = File::open(from)?;
+    let dst = File::create(to)?;
+    // SAFETY: both descriptors stay open for the call, and FICLONE takes the
+    // source descriptor as its argument by value.
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    dst.set_permissions(src.metadata()?.permissions())
+}
+
+/// Whether any extent of `path` is shared with another file, i.e. it was
+/// reflinked and neither copy has rewritten those bytes since.
+pub fn shares_extents(path: &Path) -> io::Result<bool> {
+    let file = File::open(path)?;
+    let mut start = 0;
+    loop {
+        let mut map = Fiemap {
+            fm_start: start,
+            fm_length: u64::MAX - start,
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_mapped_extents: 0,
+            fm_extent_count: EXTENTS_PER_CALL as u32,
+            fm_reserved: 0,
+            fm_extents: [FiemapExtent::default(); EXTENTS_PER_CALL],
+        };
+        // SAFETY: `map` is a correctly sized `struct fiemap` with room for
+        // `fm_extent_count` extents, and outlives the call.
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut map) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let extents = &map.fm_extents[..(map.fm_mapped_extents as usize).min(EXTENTS_PER_CALL)];
+        if extents
+            .iter()
+            .any(|e| e.fe_flags & FIEMAP_EXTENT_SHARED != 0)
+        {
+            return Ok(true);
+        }
+        match extents.last() {
+            Some(last) if last.fe_flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.fe_logical + last.fe_length;
+            }
+            _ => return Ok(false),
+        }
+    }
+}
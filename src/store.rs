@@ -0,0 +1,1466 @@
+use crate::config::Config;
+use crate::formats::same_format;
+use crate::reflink::{copy_file, shares_extents};
+use crate::{chown_path, home_dir_for_uid, Owner};
+use anyhow::{anyhow, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{open, openat, renameat, OFlag};
+use nix::sys::stat::{fstat, Mode};
+use nix::unistd::{unlinkat, UnlinkatFlags};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{fchown, lchown, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// Index kept in every version directory.
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Extended attribute holding the name of a file's version directory.
+pub const LINEAGE_XATTR: &str = "user.morph-bang.lineage";
+
+/// Suffix of versions and blobs stored compressed with zstd.
+pub const COMPRESSED_EXT: &str = "zst";
+
+/// Extension of folder snapshots, which hold the folder as a tar.
+pub const FOLDER_EXT: &str = "folder";
+
+/// What `file` reports for a directory, recorded for folder snapshots.
+pub const FOLDER_MIME: &str = "inode/directory";
+
+/// Smallest file [`worth_compressing`].
+const COMPRESS_MIN_SIZE: u64 = 4096;
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Formats that are commonly stored without compression of their own.
+const COMPRESSIBLE_MIMES: &[&str] = &[
+    "application/json",
+    "application/postscript",
+    "application/rtf",
+    "application/x-tar",
+    "application/xml",
+    "audio/aiff",
+    "audio/vnd.wave",
+    "audio/wav",
+    "audio/x-aiff",
+    "audio/x-wav",
+    "image/bmp",
+    "image/fits",
+    "image/svg+xml",
+    "image/tiff",
+    "image/vnd.adobe.photoshop",
+    "image/x-ms-bmp",
+    "image/x-portable-anymap",
+    "image/x-portable-bitmap",
+    "image/x-portable-graymap",
+    "image/x-portable-pixmap",
+    "image/x-tga",
+    FOLDER_MIME,
+];
+
+/// Directory in the store root holding each distinct content once, as
+/// `blobs/<first two hex digits>/<blake3 hex>[.zst]`. Version files are hard links
+/// to these, so a blob is in use for as long as its link count exceeds one.
+pub const BLOBS_DIR: &str = "blobs";
+
+/// Describes a version directory, so the store can be browsed and rebuilt
+/// without reversing the hashed directory name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Manifest {
+    /// The path stem the directory is keyed by, e.g. `/home/ana/photo`.
+    pub stem: PathBuf,
+    pub uid: u32,
+    /// Oldest first, like the version files themselves.
+    pub versions: Vec<VersionRecord>,
+}
+
+/// What was known about a file when it was stored.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VersionRecord {
+    /// Name of the version file inside the directory.
+    pub file: String,
+    /// Seconds since the Unix epoch.
+    pub stored_at: u64,
+    /// The path the file was stored from, usually the trigger name.
+    pub source_path: PathBuf,
+    /// The name the file had before it was renamed to trigger, e.g.
+    /// `photo.jpeg`.
+    pub original_name: String,
+    /// blake3 of the contents, in hex.
+    pub hash: String,
+    pub size: u64,
+    pub mime: String,
+    /// Permission bits of the file or folder when it was stored. Versions
+    /// stored before these were recorded have none.
+    pub mode: Option<u32>,
+    /// The trigger extension as typed, e.g. `!webp@q80+avif`.
+    pub trigger: String,
+    /// What the trigger produced, e.g. `webp@q80`.
+    pub targets: Vec<String>,
+    /// Hash of the version this content was converted from, if any.
+    pub converted_from: Option<String>,
+    /// Hashes of the files converted or restored from this content.
+    pub converted_to: Vec<String>,
+    /// The options each hash in `converted_to` was converted with, empty
+    /// for none. Conversions recorded before these were have no entry.
+    pub conversion_options: BTreeMap<String, String>,
+}
+
+/// What `store_version` records about a version besides its bytes.
+pub struct Origin<'a> {
+    /// The file's own name before it was renamed to trigger, e.g.
+    /// `photo.jpeg`.
+    pub name: &'a str,
+    pub mime: &'a str,
+    pub ext: &'a str,
+    pub trigger: &'a str,
+    pub targets: Vec<String>,
+}
+
+impl Manifest {
+    /// Reads the manifest of `version_dir`. A directory without one, such
+    /// as a store written by an older release, yields an empty manifest.
+    pub fn load(version_dir: &Path) -> Result<Self> {
+        let path = version_dir.join(MANIFEST_NAME);
+        let raw = match fs::read(&path) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        serde_json::from_slice(&raw).with_context(|| format!("invalid manifest {}", path.display()))
+    }
+
+    /// Replaces the manifest atomically: readers see the old or the new
+    /// file, never a partial one. The directory is pinned and the file
+    /// written through it, so a symlink in the user's store cannot redirect
+    /// the write.
+    pub fn save(&self, version_dir: &Path, uid: u32, gid: u32) -> Result<()> {
+        let path = version_dir.join(MANIFEST_NAME);
+        let mut body = serde_json::to_vec_pretty(self)?;
+        body.push(b'\n');
+        PinnedDir::open(version_dir, uid)
+            .and_then(|dir| {
+                let (name, tmp) = (OsStr::new(MANIFEST_NAME), format!(".{MANIFEST_NAME}.tmp"));
+                replace_file_at(dir.as_fd(), name, tmp.as_ref(), &body, uid, gid, 0o644)
+                    .map_err(Into::into)
+            })
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn record(&self, version: &Path) -> Option<&VersionRecord> {
+        let name = version.file_name()?.to_str()?;
+        self.versions.iter().find(|r| r.file == name)
+    }
+
+    /// The content `hash` was converted from, if recorded.
+    fn parent_of(&self, hash: &str) -> Option<&str> {
+        self.versions.iter().rev().find_map(|r| {
+            if r.converted_to.iter().any(|h| h == hash) {
+                Some(r.hash.as_str())
+            } else if r.hash == hash {
+                r.converted_from.as_deref()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The options `parent` was converted to `child` with, if recorded.
+    fn conversion_options(&self, parent: &str, child: &str) -> Option<&str> {
+        self.versions
+            .iter()
+            .rev()
+            .filter(|r| r.hash == parent)
+            .find_map(|r| r.conversion_options.get(child))
+            .map(String::as_str)
+    }
+
+    /// Whether content `a` was converted to `b` or from it, in either
+    /// direction.
+    pub fn linked(&self, a: &str, b: &str) -> bool {
+        let points_to = |r: &VersionRecord, other: &str| {
+            r.converted_to.iter().any(|h| h == other) || r.converted_from.as_deref() == Some(other)
+        };
+        self.versions
+            .iter()
+            .any(|r| (r.hash == a && points_to(r, b)) || (r.hash == b && points_to(r, a)))
+    }
+}
+
+/// A stored version that some content was converted from, directly or
+/// through further conversions.
+pub struct Ancestor {
+    pub version: PathBuf,
+    pub hash: String,
+    pub mime: String,
+    pub ext: String,
+    pub original_name: String,
+    /// The options this ancestor was converted with towards `hash`, when
+    /// they were recorded.
+    pub options: Option<String>,
+}
+
+/// The stored versions content `hash` descends from, nearest first: its
+/// source, that source's source, and so on.
+pub fn ancestors(version_dir: &Path, hash: &str) -> Vec<Ancestor> {
+    let Ok(manifest) = Manifest::load(version_dir) else {
+        return Vec::new();
+    };
+    let mut seen = vec![hash];
+    let mut found = Vec::new();
+    let mut current = hash;
+    while let Some(parent) = manifest.parent_of(current) {
+        if seen.contains(&parent) {
+            break;
+        }
+        seen.push(parent);
+        let stored = manifest
+            .versions
+            .iter()
+            .rev()
+            .find(|r| r.hash == parent && version_dir.join(&r.file).is_file());
+        if let Some(record) = stored {
+            let version = version_dir.join(&record.file);
+            found.push(Ancestor {
+                ext: version_ext(&version).unwrap_or_default().to_string(),
+                version,
+                hash: record.hash.clone(),
+                mime: record.mime.clone(),
+                original_name: record.original_name.clone(),
+                options: manifest
+                    .conversion_options(parent, current)
+                    .map(str::to_string),
+            });
+        }
+        current = parent;
+    }
+    found
+}
+
+/// Notes in the manifest that `output_hash` was produced from the stored
+/// content `source_hash` with `options` (empty for none). Without a stored
+/// version of the source, as after a destructive trigger, there is nothing
+/// to note it on.
+pub fn record_conversion(
+    version_dir: &Path,
+    source_hash: &str,
+    output_hash: &str,
+    options: &str,
+    uid: u32,
+    gid: u32,
+) -> Result<()> {
+    let mut manifest = Manifest::load(version_dir)?;
+    let Some(record) = manifest
+        .versions
+        .iter_mut()
+        .rev()
+        .find(|r| r.hash == source_hash)
+    else {
+        return Ok(());
+    };
+    if record.converted_to.iter().any(|h| h == output_hash) {
+        return Ok(());
+    }
+    record.converted_to.push(output_hash.to_string());
+    record
+        .conversion_options
+        .insert(output_hash.to_string(), options.to_string());
+    manifest.save(version_dir, uid, gid)
+}
+
+/// Writes a version to `destination`. A folder snapshot is unpacked into a
+/// new directory there, owned by `owner` throughout. Without `mode_override`
+/// the version gets back the permissions recorded when it was stored.
+pub fn restore_version_file(
+    cfg: &Config,
+    version_file: &Path,
+    destination: &Path,
+    owner: Owner,
+    mode_override: Option<u32>,
+) -> Result<()> {
+    let mode = mode_override.or_else(|| recorded_mode(version_file));
+    if is_folder_version(version_file) {
+        unpack_folder(version_file, destination, owner).with_context(|| {
+            format!(
+                "failed to restore folder {} -> {}",
+                version_file.display(),
+                destination.display()
+            )
+        })?;
+        if let Some(mode) = mode {
+            fs::set_permissions(destination, fs::Permissions::from_mode(mode))?;
+        }
+        return Ok(());
+    }
+    let copied = if is_compressed(version_file) {
+        decompress_file(version_file, destination)
+    } else {
+        copy_file(version_file, destination, cfg.versions.reflink).map_err(Into::into)
+    };
+    copied.with_context(|| {
+        format!(
+            "failed to restore version {} -> {}",
+            version_file.display(),
+            destination.display()
+        )
+    })?;
+    owner.chown(destination)?;
+    // The version file is a link to a blob shared with other versions, so
+    // its own mode says nothing about this one.
+    let mode = mode.unwrap_or(0o644);
+    fs::set_permissions(destination, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+/// The permissions `version_file` was stored with, from its manifest.
+fn recorded_mode(version_file: &Path) -> Option<u32> {
+    let manifest = Manifest::load(version_file.parent()?).ok()?;
+    manifest.record(version_file)?.mode
+}
+
+fn unpack_folder(version_file: &Path, destination: &Path, owner: Owner) -> Result<()> {
+    fs::create_dir(destination)?;
+    let result = (|| -> Result<()> {
+        let mut archive = tar::Archive::new(open_version(version_file)?);
+        archive.set_preserve_permissions(true);
+        // Unpacking a file touches its directory, so directory times are
+        // set last; otherwise the folder would no longer match its snapshot.
+        let mut dirs = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type().is_dir() {
+                let mtime = UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?);
+                dirs.push((destination.join(entry.path()?), mtime));
+            }
+            entry.unpack_in(destination)?;
+        }
+        for (dir, mtime) in dirs.iter().rev() {
+            fs::File::open(dir)?.set_modified(*mtime)?;
+        }
+        for entry in WalkDir::new(destination) {
+            lchown(entry?.path(), Some(owner.uid), Some(owner.gid))?;
+        }
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_dir_all(destination);
+    }
+    result
+}
+
+/// Writes the contents of `dir` to `out` as a tar, in name order and with
+/// permissions and times, so an unchanged folder always packs to the same
+/// bytes and its snapshots share a blob. Symlinks are stored as links.
+fn pack_folder(dir: &Path, out: impl Write) -> Result<()> {
+    let mut builder = tar::Builder::new(out);
+    builder.follow_symlinks(false);
+    for entry in WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let name = entry.path().strip_prefix(dir)?;
+        builder
+            .append_path_with_name(entry.path(), name)
+            .with_context(|| format!("failed to snapshot {}", entry.path().display()))?;
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+fn decompress_file(version_file: &Path, destination: &Path) -> Result<()> {
+    let mut output = fs::File::create(destination)?;
+    zstd::stream::copy_decode(fs::File::open(version_file)?, &mut output)?;
+    Ok(())
+}
+
+/// Reads a version's contents, decompressing if it is stored compressed.
+pub fn open_version(version: &Path) -> Result<Box<dyn Read>> {
+    let file =
+        fs::File::open(version).with_context(|| format!("failed to read {}", version.display()))?;
+    if is_compressed(version) {
+        Ok(Box::new(zstd::stream::Decoder::new(file)?))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// blake3 of a version's contents, as they were before compression.
+pub fn hash_version(version: &Path) -> Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update_reader(open_version(version)?)
+        .with_context(|| format!("failed to read {}", version.display()))?;
+    Ok(hasher.finalize())
+}
+
+pub fn is_folder_version(version: &Path) -> bool {
+    version_ext(version) == Some(FOLDER_EXT)
+}
+
+pub fn is_compressed(version: &Path) -> bool {
+    version.extension().is_some_and(|e| e == COMPRESSED_EXT)
+}
+
+/// The format a version was stored as, e.g. `png` for both
+/// `...-0000.png` and `...-0000.png.zst`.
+pub fn version_ext(version: &Path) -> Option<&str> {
+    let path = if is_compressed(version) {
+        Path::new(version.file_stem()?)
+    } else {
+        version
+    };
+    path.extension()?.to_str()
+}
+
+/// The version directory for `path`: the one its lineage id names, so
+/// history follows a file that was moved or renamed, otherwise the one keyed
+/// by its path. The path key covers new files, files copied by a tool that
+/// drops extended attributes, and filesystems without them.
+pub fn version_dir_for_path(cfg: &Config, path: &Path, uid: u32) -> Result<PathBuf> {
+    if let Some(id) = lineage_id(path) {
+        let dir = store_root(cfg, uid)?.join(id);
+        if dir.is_dir() {
+            return Ok(dir);
+        }
+    }
+    path_version_dir(cfg, path, uid)
+}
+
+/// History is kept per file stem, so `photo.png`, `photo.jpg` and the
+/// trigger `photo.!webp` all share one version directory.
+pub fn path_version_dir(cfg: &Config, path: &Path, uid: u32) -> Result<PathBuf> {
+    stem_version_dir(cfg, &path.with_extension(""), uid)
+}
+
+/// The version directory keyed by `stem`, a path without its extension.
+pub fn stem_version_dir(cfg: &Config, stem: &Path, uid: u32) -> Result<PathBuf> {
+    Ok(store_root(cfg, uid)?.join(stable_path_key(stem, uid)))
+}
+
+pub fn store_root(cfg: &Config, uid: u32) -> Result<PathBuf> {
+    Ok(home_dir_for_uid(uid)?.join(&cfg.versions.store_dir))
+}
+
+/// The version directory name recorded on `path`, if any.
+pub fn lineage_id(path: &Path) -> Option<String> {
+    let raw = xattr::get(path, LINEAGE_XATTR).ok()??;
+    String::from_utf8(raw).ok().filter(|id| is_lineage_id(id))
+}
+
+/// Version directory names are blake3 hashes in hex.
+pub fn is_lineage_id(id: &str) -> bool {
+    id.len() == 64 && is_hex(id)
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes()
+        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Records `version_dir` as the history of `path`.
+pub fn tag_lineage(path: &Path, version_dir: &Path) -> std::io::Result<()> {
+    let id = file_name(version_dir);
+    if lineage_id(path).is_some_and(|current| current == id) {
+        return Ok(());
+    }
+    xattr::set(path, LINEAGE_XATTR, id.as_bytes())
+}
+
+/// Moves versions from the old per-output-path directory of `clean_path`
+/// into the per-stem `version_dir`.
+pub fn adopt_legacy_versions(cfg: &Config, clean_path: &Path, version_dir: &Path, uid: u32) {
+    let Ok(home_dir) = home_dir_for_uid(uid) else {
+        return;
+    };
+    let legacy_dir = home_dir
+        .join(&cfg.versions.store_dir)
+        .join(legacy_path_key(clean_path, uid));
+    let Ok(entries) = fs::read_dir(&legacy_dir) else {
+        return;
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let dest = version_dir.join(entry.file_name());
+        if !dest.exists() {
+            let _ = fs::rename(entry.path(), dest);
+        }
+    }
+    let _ = fs::remove_dir(&legacy_dir);
+}
+
+pub fn ensure_version_paths_owned(version_dir: &Path, uid: u32, gid: u32) -> Result<()> {
+    let versions_root = version_dir
+        .parent()
+        .ok_or_else(|| anyhow!("invalid version directory"))?;
+    let missing: Vec<&Path> = version_dir
+        .ancestors()
+        .take_while(|dir| !dir.exists())
+        .collect();
+
+    fs::create_dir_all(version_dir).context("failed to create version directory")?;
+
+    for dir in missing {
+        chown_path(dir, uid, gid)?;
+    }
+    chown_path(versions_root, uid, gid)?;
+    chown_path(version_dir, uid, gid)?;
+    Ok(())
+}
+
+/// Stores `source_path` in `version_dir` and records it in the manifest.
+/// Identical contents share one blob however often they are stored, and a
+/// directory is stored as a snapshot of everything in it. A
+/// manifest that cannot be updated is logged rather than failing the
+/// conversion, since the version itself is safely stored.
+pub fn store_version(
+    cfg: &Config,
+    source_path: &Path,
+    version_dir: &Path,
+    origin: &Origin,
+    uid: u32,
+    gid: u32,
+) -> Result<PathBuf> {
+    let mut ext = sanitize_ext(if source_path.is_dir() {
+        FOLDER_EXT
+    } else if origin.ext.is_empty() {
+        "bin"
+    } else {
+        origin.ext
+    });
+    let blob = store_blob(cfg, source_path, version_dir, origin.mime, uid, gid)?;
+    if is_compressed(&blob.path) {
+        ext = format!("{ext}.{COMPRESSED_EXT}");
+    }
+    let version_file = next_version_path(version_dir, &ext)?;
+    // At the filesystem's link limit the version gets a private copy.
+    if let Err(err) = fs::hard_link(&blob.path, &version_file) {
+        eprintln!(
+            "morph-bang: storing {} unshared: {err}",
+            version_file.display()
+        );
+        copy_file(&blob.path, &version_file, cfg.versions.reflink)?;
+        chown_path(&version_file, uid, gid)?;
+    }
+
+    if let Err(err) = record_version(
+        source_path,
+        version_dir,
+        &version_file,
+        &blob,
+        origin,
+        uid,
+        gid,
+    ) {
+        eprintln!("morph-bang: {err:#}");
+    }
+    Ok(version_file)
+}
+
+/// A blob holding some stored contents.
+struct Blob {
+    /// The shard directory the blob is in, which `path` resolves through.
+    _shard: PinnedDir,
+    path: PathBuf,
+    /// blake3 of the uncompressed contents, in hex.
+    hash: String,
+    /// Size of the uncompressed contents.
+    size: u64,
+}
+
+/// Copies `source_path`, or packs it if it is a directory, into the blob
+/// store unless its contents are already there, compressed if
+/// [`worth_compressing`] and it actually saves space.
+fn store_blob(
+    cfg: &Config,
+    source_path: &Path,
+    version_dir: &Path,
+    mime: &str,
+    uid: u32,
+    gid: u32,
+) -> Result<Blob> {
+    let root = version_dir
+        .parent()
+        .ok_or_else(|| anyhow!("invalid version directory"))?;
+    let store = PinnedDir::open(root, uid)?;
+    create_owned_dir(&store.path().join(BLOBS_DIR), uid, gid)?;
+    let blobs_dir = store.open_dir(BLOBS_DIR, uid)?;
+    let blobs = blobs_dir.path();
+    let unique = format!(
+        ".{}-{}",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0)
+    );
+    let tmp = blobs.join(format!("{unique}.tmp"));
+    let packed_tmp = blobs.join(format!("{unique}.{COMPRESSED_EXT}.tmp"));
+
+    let result = (|| -> Result<Blob> {
+        // Hash the copy rather than the source, so the key always matches
+        // the stored bytes even if the source changes meanwhile.
+        if source_path.is_dir() {
+            pack_folder(source_path, fs::File::create(&tmp)?)?;
+        } else {
+            copy_file(source_path, &tmp, cfg.versions.reflink)
+                .with_context(|| format!("failed to store {}", source_path.display()))?;
+        }
+        let hash = hash_file(&tmp)?.to_hex().to_string();
+        let size = fs::metadata(&tmp)?.len();
+        let shard_name = &hash[..2];
+        create_owned_dir(&blobs.join(shard_name), uid, gid)?;
+        let shard = blobs_dir.open_dir(shard_name, uid)?;
+        let plain = shard.path().join(blob_name(&hash, false));
+        let packed = shard.path().join(blob_name(&hash, true));
+        if let Some(existing) = [&plain, &packed].into_iter().find(|b| b.is_file()) {
+            return Ok(Blob {
+                _shard: shard,
+                path: existing.clone(),
+                hash,
+                size,
+            });
+        }
+
+        let path = if cfg.versions.compress
+            && worth_compressing(mime, size)
+            && compress_file(&tmp, &packed_tmp)?
+        {
+            chown_path(&packed_tmp, uid, gid)?;
+            fs::rename(&packed_tmp, &packed)?;
+            packed
+        } else {
+            chown_path(&tmp, uid, gid)?;
+            fs::rename(&tmp, &plain)?;
+            plain
+        };
+        Ok(Blob {
+            _shard: shard,
+            path,
+            hash,
+            size,
+        })
+    })();
+    let _ = fs::remove_file(&tmp);
+    let _ = fs::remove_file(&packed_tmp);
+    result
+}
+
+/// Where the blob for contents `hash` lives in `blobs`.
+pub fn blob_path(blobs: &Path, hash: &str, compressed: bool) -> PathBuf {
+    blobs
+        .join(hash.get(..2).unwrap_or(hash))
+        .join(blob_name(hash, compressed))
+}
+
+fn blob_name(hash: &str, compressed: bool) -> String {
+    if compressed {
+        format!("{hash}.{COMPRESSED_EXT}")
+    } else {
+        hash.to_string()
+    }
+}
+
+/// Whether `name` is a blob in a shard: a content hash, maybe compressed.
+pub fn is_blob_name(name: &str) -> bool {
+    let hash = name
+        .strip_suffix(COMPRESSED_EXT)
+        .and_then(|n| n.strip_suffix('.'))
+        .unwrap_or(name);
+    is_lineage_id(hash)
+}
+
+/// Whether `name` is a blob shard: the first two hex digits of the hashes
+/// in it.
+pub fn is_shard_name(name: &str) -> bool {
+    name.len() == 2 && is_hex(name)
+}
+
+/// A directory of a user's store, opened without following symlinks and
+/// checked to belong to that user. Paths under [`PinnedDir::path`] resolve
+/// through the open descriptor, so a daemon running as root cannot be led
+/// out of the store by a directory swapped for a symlink.
+pub struct PinnedDir {
+    fd: OwnedFd,
+    /// The path it was opened as, for messages.
+    shown: PathBuf,
+}
+
+impl PinnedDir {
+    pub fn open(path: &Path, uid: u32) -> Result<Self> {
+        let fd =
+            open(path, Self::FLAGS, Mode::empty()).map_err(|err| Self::refusal(path, path, err))?;
+        Self::owned_by(fd, path.to_path_buf(), uid)
+    }
+
+    /// Opens the entry `name` of this directory the same way.
+    pub fn open_dir(&self, name: &str, uid: u32) -> Result<Self> {
+        let shown = self.shown.join(name);
+        if name.contains('/') || name == ".." {
+            return Err(anyhow!("invalid store entry {}", shown.display()));
+        }
+        let fd = openat(&self.fd, name, Self::FLAGS, Mode::empty())
+            .map_err(|err| Self::refusal(&self.path().join(name), &shown, err))?;
+        Self::owned_by(fd, shown, uid)
+    }
+
+    /// The directory as a path, valid while `self` is.
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/self/fd/{}", self.fd.as_raw_fd()))
+    }
+
+    const FLAGS: OFlag = OFlag::O_RDONLY
+        .union(OFlag::O_DIRECTORY)
+        .union(OFlag::O_NOFOLLOW)
+        .union(OFlag::O_CLOEXEC);
+
+    /// Why `path`, shown as `shown`, could not be opened. With
+    /// `O_DIRECTORY` a symlink fails as not being a directory.
+    fn refusal(path: &Path, shown: &Path, err: Errno) -> anyhow::Error {
+        if fs::symlink_metadata(path).is_ok_and(|m| m.is_symlink()) {
+            return anyhow!("refusing {}: it is a symlink", shown.display());
+        }
+        anyhow!("failed to open {}: {err}", shown.display())
+    }
+
+    fn owned_by(fd: OwnedFd, shown: PathBuf, uid: u32) -> Result<Self> {
+        let stat = fstat(&fd).with_context(|| format!("failed to read {}", shown.display()))?;
+        if stat.st_uid != uid {
+            return Err(anyhow!(
+                "refusing {}: it is not owned by uid {uid}",
+                shown.display()
+            ));
+        }
+        Ok(Self { fd, shown })
+    }
+}
+
+impl AsFd for PinnedDir {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Creates `name` in `dir` for `uid` with permissions `mode`, failing if
+/// anything is there already, a symlink included. Ownership and mode are set
+/// on the open file, so a root daemon never changes a file it did not just
+/// create.
+pub fn create_file_at(
+    dir: BorrowedFd,
+    name: &OsStr,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+) -> std::io::Result<fs::File> {
+    let flags = OFlag::O_WRONLY
+        .union(OFlag::O_CREAT)
+        .union(OFlag::O_EXCL)
+        .union(OFlag::O_NOFOLLOW)
+        .union(OFlag::O_CLOEXEC);
+    let file = fs::File::from(openat(dir, name, flags, Mode::from_bits_truncate(0o600))?);
+    fchown(&file, Some(uid), Some(gid))?;
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    Ok(file)
+}
+
+/// Replaces `name` in `dir` with `body` atomically, writing it first to
+/// `tmp` as [`create_file_at`] does. Whatever `tmp` names beforehand, such as
+/// a file left by a crash, is unlinked rather than written through.
+pub fn replace_file_at(
+    dir: BorrowedFd,
+    name: &OsStr,
+    tmp: &OsStr,
+    body: &[u8],
+    uid: u32,
+    gid: u32,
+    mode: u32,
+) -> std::io::Result<()> {
+    match unlinkat(dir, tmp, UnlinkatFlags::NoRemoveDir) {
+        Ok(()) | Err(Errno::ENOENT) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let result = (|| -> std::io::Result<()> {
+        let mut file = create_file_at(dir, tmp, uid, gid, mode)?;
+        file.write_all(body)?;
+        file.sync_all()?;
+        renameat(dir, tmp, dir, name)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = unlinkat(dir, tmp, UnlinkatFlags::NoRemoveDir);
+    }
+    result
+}
+
+/// Whether contents of this type and size usually shrink: text, and images
+/// and audio that are stored uncompressed. Small files are not worth it.
+fn worth_compressing(mime: &str, size: u64) -> bool {
+    size >= COMPRESS_MIN_SIZE && (mime.starts_with("text/") || COMPRESSIBLE_MIMES.contains(&mime))
+}
+
+/// Writes `raw` compressed to `out`, with `raw`'s permissions. Returns
+/// whether that saved at least a tenth of the size.
+fn compress_file(raw: &Path, out: &Path) -> Result<bool> {
+    let input = fs::File::open(raw)?;
+    let raw_len = input.metadata()?.len();
+    let mut output = fs::File::create(out)?;
+    zstd::stream::copy_encode(&input, &mut output, COMPRESSION_LEVEL)
+        .with_context(|| format!("failed to compress {}", raw.display()))?;
+    output.set_permissions(input.metadata()?.permissions())?;
+    Ok(output.metadata()?.len() * 10 <= raw_len * 9)
+}
+
+pub fn create_owned_dir(dir: &Path, uid: u32, gid: u32) -> Result<()> {
+    match fs::create_dir(dir) {
+        Ok(()) => chown_path(dir, uid, gid),
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("failed to create {}", dir.display())),
+    }
+}
+
+fn record_version(
+    source_path: &Path,
+    version_dir: &Path,
+    version_file: &Path,
+    blob: &Blob,
+    origin: &Origin,
+    uid: u32,
+    gid: u32,
+) -> Result<()> {
+    let mut manifest = Manifest::load(version_dir)?;
+    let converted_from = manifest
+        .versions
+        .iter()
+        .rev()
+        .find(|r| r.converted_to.contains(&blob.hash))
+        .map(|r| r.hash.clone());
+    manifest.stem = source_path.with_extension("");
+    manifest.uid = uid;
+    manifest.versions.push(VersionRecord {
+        file: file_name(version_file),
+        stored_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        source_path: source_path.to_path_buf(),
+        original_name: origin.name.to_string(),
+        hash: blob.hash.clone(),
+        size: blob.size,
+        mime: origin.mime.to_string(),
+        mode: fs::symlink_metadata(source_path)
+            .ok()
+            .map(|m| m.permissions().mode() & 0o7777),
+        trigger: origin.trigger.to_string(),
+        targets: origin.targets.clone(),
+        converted_from,
+        converted_to: Vec::new(),
+        conversion_options: BTreeMap::new(),
+    });
+    manifest.save(version_dir, uid, gid)
+}
+
+fn next_version_path(version_dir: &Path, ext: &str) -> Result<PathBuf> {
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("clock error")?
+        .as_nanos();
+    let pid = std::process::id();
+    for seq in 0..1024u32 {
+        let candidate = version_dir.join(format!("{ts:020}-{pid:05}-{seq:04}.{ext}"));
+        if !candidate.exists() {
+            return Ok(candidate);
+        }
+    }
+    Err(anyhow!(
+        "failed to allocate unique version filename in {}",
+        version_dir.display()
+    ))
+}
+
+fn sanitize_ext(ext: &str) -> String {
+    let sanitized: String = ext
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if sanitized.is_empty() {
+        "bin".to_string()
+    } else {
+        sanitized
+    }
+}
+
+fn stable_path_key(path: &Path, uid: u32) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"morph-bang:v2:stem-key");
+    hasher.update(&uid.to_be_bytes());
+    hasher.update(&[0]);
+    hasher.update(path.as_os_str().as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+fn legacy_path_key(path: &Path, uid: u32) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"morph-bang:v1:path-key");
+    hasher.update(&uid.to_be_bytes());
+    hasher.update(&[0]);
+    hasher.update(path.as_os_str().as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+pub fn find_latest_version_by_ext(version_dir: &Path, target_ext: &str) -> Option<PathBuf> {
+    latest_version_where(version_dir, |p| {
+        version_ext(p).is_some_and(|e| same_format(e, target_ext))
+    })
+}
+
+/// The newest version in `target_ext` that content `current_hash` was
+/// converted to or from. A file edited since its conversion matches none,
+/// so it is converted again rather than replaced by a stale version. A
+/// version stored before manifests were written is matched by hashing its
+/// contents, since the conversions recorded since then name it that way.
+pub fn find_lineage_version(
+    version_dir: &Path,
+    target_ext: &str,
+    current_hash: &str,
+) -> Option<PathBuf> {
+    let manifest = Manifest::load(version_dir).ok()?;
+    latest_version_where(version_dir, |p| {
+        if !version_ext(p).is_some_and(|e| same_format(e, target_ext)) {
+            return false;
+        }
+        match manifest.record(p) {
+            Some(record) => manifest.linked(&record.hash, current_hash),
+            None => hash_version(p).is_ok_and(|hash| manifest.linked(&hash.to_hex(), current_hash)),
+        }
+    })
+}
+
+/// The newest version in `version_dir` accepted by `pred`.
+pub fn latest_version_where(version_dir: &Path, pred: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+    list_versions(version_dir)
+        .into_iter()
+        .rev()
+        .find(|p| pred(p))
+}
+
+/// Every version file in `version_dir`, oldest first. Version names start
+/// with a fixed-width timestamp, so name order is age order; the manifest
+/// and anything else in the directory is skipped.
+pub fn list_versions(version_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(version_dir) else {
+        return Vec::new();
+    };
+    let mut versions: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && is_version_name(&file_name(p)))
+        .collect();
+    versions.sort();
+    versions
+}
+
+/// When `version` was stored, from the timestamp its name starts with.
+pub fn stored_nanos(version: &Path) -> Option<u128> {
+    let name = version.file_name()?.to_str()?;
+    if !is_version_name(name) {
+        return None;
+    }
+    name[..20].parse().ok()
+}
+
+/// Version files are named `<20-digit timestamp>-...`.
+pub fn is_version_name(name: &str) -> bool {
+    name.len() > 20 && name.as_bytes()[..20].iter().all(u8::is_ascii_digit)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// How a version holds its bytes: shared with other versions through its
+/// blob, reflinked with a file outside the store, or owned outright.
+#[derive(Debug, Clone, Copy)]
+pub struct Storage {
+    /// Other versions linked to the same blob.
+    pub linked_versions: u64,
+    /// Some extents are shared with another file, e.g. the original.
+    pub reflinked: bool,
+}
+
+impl Storage {
+    /// How `version`, holding content `hash`, keeps its bytes. Its link
+    /// count only says how many versions share them while it is a link to
+    /// the store's blob for that content; a version copied in place of a
+    /// link, or whose blob is gone, counts as unshared.
+    pub fn of(version: &Path, hash: &str) -> Result<Self> {
+        let meta = fs::symlink_metadata(version)
+            .with_context(|| format!("failed to read {}", version.display()))?;
+        let blob = version
+            .parent()
+            .and_then(Path::parent)
+            .map(|store| blob_path(&store.join(BLOBS_DIR), hash, is_compressed(version)))
+            .and_then(|blob| fs::symlink_metadata(blob).ok());
+        let is_blob = blob.is_some_and(|b| b.dev() == meta.dev() && b.ino() == meta.ino());
+        Ok(Self {
+            // One link is the blob itself and one is this version.
+            linked_versions: if is_blob {
+                meta.nlink().saturating_sub(2)
+            } else {
+                0
+            },
+            reflinked: shares_extents(version).unwrap_or(false),
+        })
+    }
+
+    /// `shared`, `reflink` or `owned`, for listings.
+    pub fn label(&self) -> &'static str {
+        if self.linked_versions > 0 {
+            "shared"
+        } else if self.reflinked {
+            "reflink"
+        } else {
+            "owned"
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        match self.linked_versions {
+            0 => {}
+            1 => parts.push("shared with 1 other version".to_string()),
+            n => parts.push(format!("shared with {n} other versions")),
+        }
+        if self.reflinked {
+            parts.push("extents shared with another file (reflink)".to_string());
+        }
+        if parts.is_empty() {
+            "owns its bytes".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+/// blake3 of a file, or of a folder as a snapshot of it would store it.
+pub fn hash_path(path: &Path) -> Result<blake3::Hash> {
+    if !path.is_dir() {
+        return hash_file(path);
+    }
+    let mut hasher = blake3::Hasher::new();
+    pack_folder(path, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+pub fn hash_file(path: &Path) -> Result<blake3::Hash> {
+    let file =
+        fs::File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(file)?;
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{config_with_store, supports_lineage, TempDir};
+    use nix::unistd::{Gid, Uid};
+
+    fn record(file: &str, hash: &str, from: Option<&str>, to: &[(&str, &str)]) -> VersionRecord {
+        VersionRecord {
+            file: file.to_string(),
+            hash: hash.to_string(),
+            converted_from: from.map(str::to_string),
+            converted_to: to.iter().map(|(h, _)| h.to_string()).collect(),
+            conversion_options: to
+                .iter()
+                .map(|(h, o)| (h.to_string(), o.to_string()))
+                .collect(),
+            ..VersionRecord::default()
+        }
+    }
+
+    /// A version directory holding a file for every record.
+    fn version_dir(name: &str, versions: Vec<VersionRecord>) -> TempDir {
+        let dir = TempDir::new(name);
+        for record in &versions {
+            fs::write(dir.join(&record.file), &record.hash).unwrap();
+        }
+        let manifest = Manifest {
+            versions,
+            ..Manifest::default()
+        };
+        manifest
+            .save(&dir, Uid::current().as_raw(), Gid::current().as_raw())
+            .unwrap();
+        dir
+    }
+
+    fn hashes(ancestors: &[Ancestor]) -> Vec<&str> {
+        ancestors.iter().map(|a| a.hash.as_str()).collect()
+    }
+
+    #[test]
+    fn finds_parents_from_either_side_of_a_conversion() {
+        let manifest = Manifest {
+            versions: vec![
+                record("00000000000000000001-a.pdf", "a", None, &[("b", "p2")]),
+                record("00000000000000000002-b.png", "b", None, &[]),
+                record("00000000000000000003-c.jpg", "c", Some("b"), &[]),
+            ],
+            ..Manifest::default()
+        };
+        assert_eq!(manifest.parent_of("b"), Some("a"));
+        assert_eq!(manifest.parent_of("c"), Some("b"));
+        assert_eq!(manifest.parent_of("a"), None);
+        assert_eq!(manifest.conversion_options("a", "b"), Some("p2"));
+        assert_eq!(manifest.conversion_options("b", "c"), None);
+    }
+
+    #[test]
+    fn lists_ancestors_nearest_first_with_their_options() {
+        let dir = version_dir(
+            "store-chain",
+            vec![
+                record("00000000000000000001-a.pdf", "a", None, &[("b", "p2")]),
+                record("00000000000000000002-b.png", "b", None, &[("c", "q80")]),
+                record("00000000000000000003-c.jpg", "c", None, &[]),
+            ],
+        );
+        let found = ancestors(&dir, "c");
+        assert_eq!(hashes(&found), ["b", "a"]);
+        assert_eq!(found[0].ext, "png");
+        assert_eq!(found[0].options.as_deref(), Some("q80"));
+        assert_eq!(found[1].options.as_deref(), Some("p2"));
+    }
+
+    #[test]
+    fn stops_at_a_conversion_cycle() {
+        let dir = version_dir(
+            "store-cycle",
+            vec![
+                record("00000000000000000001-a.png", "a", Some("b"), &[("b", "")]),
+                record("00000000000000000002-b.jpg", "b", Some("a"), &[("a", "")]),
+            ],
+        );
+        assert_eq!(hashes(&ancestors(&dir, "a")), ["b"]);
+        assert_eq!(hashes(&ancestors(&dir, "b")), ["a"]);
+    }
+
+    #[test]
+    fn saves_the_manifest_without_following_a_planted_tmp_symlink() {
+        let dir = TempDir::new("store-manifest-tmp");
+        let outside = TempDir::new("store-manifest-outside");
+        let target = outside.join("shadow");
+        fs::write(&target, "secret").unwrap();
+        std::os::unix::fs::symlink(&target, dir.join(format!(".{MANIFEST_NAME}.tmp"))).unwrap();
+        let manifest = Manifest {
+            stem: PathBuf::from("/srv/photo"),
+            ..Manifest::default()
+        };
+        manifest
+            .save(&dir, Uid::current().as_raw(), Gid::current().as_raw())
+            .unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "secret");
+        assert_eq!(Manifest::load(&dir).unwrap().stem, Path::new("/srv/photo"));
+        assert!(!dir.join(format!(".{MANIFEST_NAME}.tmp")).exists());
+    }
+
+    #[test]
+    fn refuses_to_store_through_a_symlinked_blob_store() {
+        let root = TempDir::new("store-symlink");
+        let outside = TempDir::new("store-outside");
+        std::os::unix::fs::symlink(&*outside, root.join(BLOBS_DIR)).unwrap();
+        let version_dir = root.join("a".repeat(64));
+        fs::create_dir(&version_dir).unwrap();
+        let source = root.join("photo.png");
+        fs::write(&source, "pixels").unwrap();
+        let origin = Origin {
+            name: "photo.png",
+            mime: "image/png",
+            ext: "png",
+            trigger: "!webp",
+            targets: Vec::new(),
+        };
+        let (uid, gid) = (Uid::current().as_raw(), Gid::current().as_raw());
+
+        let err = store_version(&Config::default(), &source, &version_dir, &origin, uid, gid);
+        let err = format!("{:#}", err.unwrap_err());
+        assert!(err.contains("symlink"), "{err}");
+        assert_eq!(fs::read_dir(&*outside).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn counts_shared_versions_only_through_their_blob() {
+        let root = TempDir::new("store-storage");
+        let version_dir = root.join("a".repeat(64));
+        fs::create_dir(&version_dir).unwrap();
+        let source = root.join("photo.png");
+        fs::write(&source, "pixels").unwrap();
+        let origin = Origin {
+            name: "photo.png",
+            mime: "image/png",
+            ext: "png",
+            trigger: "!webp",
+            targets: Vec::new(),
+        };
+        let (cfg, owner) = (Config::default(), current_owner());
+        let hash = blake3::hash(b"pixels").to_hex().to_string();
+
+        let first =
+            store_version(&cfg, &source, &version_dir, &origin, owner.uid, owner.gid).unwrap();
+        assert_eq!(Storage::of(&first, &hash).unwrap().linked_versions, 0);
+        store_version(&cfg, &source, &version_dir, &origin, owner.uid, owner.gid).unwrap();
+        let storage = Storage::of(&first, &hash).unwrap();
+        assert_eq!(storage.linked_versions, 1);
+        assert_eq!(storage.label(), "shared");
+
+        // A copy in place of a link shares nothing with other versions,
+        // however many names it has.
+        let copied = version_dir.join("00000000000000000003-00001-0000.png");
+        fs::copy(&first, &copied).unwrap();
+        fs::hard_link(&copied, root.join("elsewhere")).unwrap();
+        fs::hard_link(&copied, root.join("elsewhere too")).unwrap();
+        assert_eq!(Storage::of(&copied, &hash).unwrap().linked_versions, 0);
+    }
+
+    fn current_owner() -> Owner {
+        Owner {
+            uid: Uid::current().as_raw(),
+            gid: Gid::current().as_raw(),
+            mode: 0o644,
+        }
+    }
+
+    fn packed(dir: &Path) -> Vec<u8> {
+        let mut out = Vec::new();
+        pack_folder(dir, &mut out).unwrap();
+        out
+    }
+
+    fn mode_of(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn packs_an_unchanged_folder_to_the_same_bytes() {
+        let root = TempDir::new("store-pack-twice");
+        let folder = root.join("pages");
+        fs::create_dir_all(folder.join("sub")).unwrap();
+        fs::write(folder.join("page-1.png"), "one").unwrap();
+        fs::write(folder.join("sub/page-2.png"), "two").unwrap();
+
+        let first = packed(&folder);
+        assert_eq!(first, packed(&folder));
+        assert_eq!(hash_path(&folder).unwrap(), blake3::hash(&first));
+
+        fs::write(folder.join("sub/page-2.png"), "TWO").unwrap();
+        assert_ne!(hash_path(&folder).unwrap(), blake3::hash(&first));
+    }
+
+    #[test]
+    fn unpacks_a_folder_snapshot_as_it_was_packed() {
+        let root = TempDir::new("store-pack-round-trip");
+        let folder = root.join("project");
+        fs::create_dir_all(folder.join("src/deep")).unwrap();
+        fs::write(folder.join("notes.txt"), "notes").unwrap();
+        fs::write(folder.join("src/deep/main.rs"), "fn main() {}").unwrap();
+        std::os::unix::fs::symlink("notes.txt", folder.join("link")).unwrap();
+        fs::set_permissions(folder.join("notes.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::set_permissions(folder.join("src/deep"), fs::Permissions::from_mode(0o750)).unwrap();
+        let version = root.join(format!("00000000000000000001-00001-0000.{FOLDER_EXT}"));
+        fs::write(&version, packed(&folder)).unwrap();
+
+        let restored = root.join("restored");
+        unpack_folder(&version, &restored, current_owner()).unwrap();
+        assert_eq!(
+            fs::read_to_string(restored.join("notes.txt")).unwrap(),
+            "notes"
+        );
+        assert_eq!(
+            fs::read_to_string(restored.join("src/deep/main.rs")).unwrap(),
+            "fn main() {}"
+        );
+        assert_eq!(mode_of(&restored.join("notes.txt")), 0o640);
+        assert_eq!(mode_of(&restored.join("src/deep")), 0o750);
+        let link = restored.join("link");
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("notes.txt"));
+        // Restoring times too makes the restored folder hash like the
+        // snapshot, which is how an untouched folder is recognised.
+        assert_eq!(packed(&restored), packed(&folder));
+    }
+
+    /// Bytes that do not compress, from blake3's extendable output.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        blake3::Hasher::new().finalize_xof().fill(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn only_compresses_large_enough_uncompressed_formats() {
+        assert!(worth_compressing("text/plain", COMPRESS_MIN_SIZE));
+        assert!(!worth_compressing("text/plain", COMPRESS_MIN_SIZE - 1));
+        assert!(worth_compressing("image/bmp", 1 << 20));
+        assert!(worth_compressing(FOLDER_MIME, 1 << 20));
+        assert!(!worth_compressing("image/png", 1 << 20));
+        assert!(!worth_compressing("video/mp4", 1 << 20));
+    }
+
+    #[test]
+    fn keeps_compressed_copies_only_when_they_save_a_tenth() {
+        let dir = TempDir::new("store-compress");
+        let (raw, out) = (dir.join("raw"), dir.join("raw.zst"));
+        fs::write(&raw, "line of text\n".repeat(1000)).unwrap();
+        assert!(compress_file(&raw, &out).unwrap());
+        let mut restored = Vec::new();
+        zstd::stream::copy_decode(fs::File::open(&out).unwrap(), &mut restored).unwrap();
+        assert_eq!(restored, fs::read(&raw).unwrap());
+
+        fs::write(&raw, noise(64 << 10)).unwrap();
+        assert!(!compress_file(&raw, &out).unwrap());
+    }
+
+    #[test]
+    fn reads_the_format_through_the_compressed_suffix() {
+        let plain = Path::new("00000000000000000001-00001-0000.png");
+        let packed = Path::new("00000000000000000001-00001-0000.png.zst");
+        assert_eq!(version_ext(plain), Some("png"));
+        assert_eq!(version_ext(packed), Some("png"));
+        assert!(!is_compressed(plain));
+        assert!(is_compressed(packed));
+        let folder = Path::new("00000000000000000001-00001-0000.folder.zst");
+        assert!(is_folder_version(folder));
+    }
+
+    #[test]
+    fn restores_compressed_versions_transparently() {
+        let root = TempDir::new("store-compressed-restore");
+        let version_dir = root.join("a".repeat(64));
+        fs::create_dir(&version_dir).unwrap();
+        let source = root.join("notes.txt");
+        let text = "some notes worth keeping\n".repeat(500);
+        fs::write(&source, &text).unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o600)).unwrap();
+        let origin = Origin {
+            name: "notes.txt",
+            mime: "text/plain",
+            ext: "txt",
+            trigger: "!md",
+            targets: Vec::new(),
+        };
+        let mut cfg = Config::default();
+        cfg.versions.compress = true;
+        let owner = current_owner();
+
+        let version =
+            store_version(&cfg, &source, &version_dir, &origin, owner.uid, owner.gid).unwrap();
+        assert!(is_compressed(&version));
+        assert!(fs::metadata(&version).unwrap().len() < text.len() as u64);
+        assert_eq!(
+            hash_version(&version).unwrap(),
+            blake3::hash(text.as_bytes())
+        );
+
+        let restored = root.join("restored.txt");
+        restore_version_file(&cfg, &version, &restored, owner, None).unwrap();
+        assert_eq!(fs::read_to_string(&restored).unwrap(), text);
+        assert_eq!(mode_of(&restored), 0o600);
+    }
+
+    #[test]
+    fn restores_only_versions_linked_to_the_current_content() {
+        let dir = version_dir(
+            "store-lineage",
+            vec![
+                record("00000000000000000001-a.png", "a", None, &[("b", "")]),
+                record("00000000000000000002-b.jpg", "b", Some("a"), &[]),
+            ],
+        );
+        let original = dir.join("00000000000000000001-a.png");
+        assert_eq!(find_lineage_version(&dir, "png", "b"), Some(original));
+        // The JPEG was edited after the conversion, so it is converted again.
+        assert_eq!(find_lineage_version(&dir, "png", "edited"), None);
+        assert!(Manifest::load(&dir).unwrap().linked("b", "a"));
+        assert!(!Manifest::load(&dir).unwrap().linked("a", "edited"));
+    }
+
+    #[test]
+    fn matches_a_version_the_manifest_does_not_know_by_its_contents() {
+        let legacy_hash = blake3::hash(b"a").to_hex().to_string();
+        let dir = version_dir(
+            "store-lineage-legacy",
+            vec![
+                record("00000000000000000002-b.jpg", "b", None, &[]),
+                record("00000000000000000003-c.jpg", "c", Some(&legacy_hash), &[]),
+            ],
+        );
+        let legacy = dir.join("00000000000000000001-00001-0000.png");
+        fs::write(&legacy, "a").unwrap();
+        // Nothing links the unrecorded PNG to `b`, but `c` was converted
+        // from its contents.
+        assert_eq!(find_lineage_version(&dir, "png", "b"), None);
+        assert_eq!(find_lineage_version(&dir, "png", "c"), Some(legacy));
+        assert!(find_latest_version_by_ext(&dir, "png").is_some());
+    }
+
+    #[test]
+    fn follows_the_lineage_tag_across_a_rename() {
+        let root = TempDir::new("store-lineage-tag");
+        let store = root.join("store");
+        fs::create_dir(&store).unwrap();
+        let cfg = config_with_store(&store);
+        let uid = Uid::current().as_raw();
+        let photo = root.join("photo.png");
+        fs::write(&photo, "pixels").unwrap();
+        if !supports_lineage(&photo) {
+            return;
+        }
+        let history = path_version_dir(&cfg, &photo, uid).unwrap();
+        fs::create_dir(&history).unwrap();
+
+        tag_lineage(&photo, &history).unwrap();
+        assert_eq!(lineage_id(&photo), Some(file_name(&history)));
+        let moved = root.join("holiday.png");
+        fs::rename(&photo, &moved).unwrap();
+        assert_eq!(version_dir_for_path(&cfg, &moved, uid).unwrap(), history);
+    }
+
+    #[test]
+    fn falls_back_to_the_path_key_without_a_usable_tag() {
+        let root = TempDir::new("store-lineage-fallback");
+        let store = root.join("store");
+        fs::create_dir(&store).unwrap();
+        let cfg = config_with_store(&store);
+        let uid = Uid::current().as_raw();
+        let photo = root.join("photo.png");
+        fs::write(&photo, "pixels").unwrap();
+        let by_path = path_version_dir(&cfg, &photo, uid).unwrap();
+        assert_eq!(version_dir_for_path(&cfg, &photo, uid).unwrap(), by_path);
+
+        if !supports_lineage(&photo) {
+            return;
+        }
+        // A tag naming a directory that is gone, or not an id at all, is
+        // ignored.
+        tag_lineage(&photo, &store.join("b".repeat(64))).unwrap();
+        assert_eq!(version_dir_for_path(&cfg, &photo, uid).unwrap(), by_path);
+        xattr::set(&photo, LINEAGE_XATTR, b"../../etc").unwrap();
+        assert_eq!(lineage_id(&photo), None);
+        assert_eq!(version_dir_for_path(&cfg, &photo, uid).unwrap(), by_path);
+    }
+}
@@ -0,0 +1,55 @@
+use crate::config::Config;
+use std::fs;
+use std::ops::Deref;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// An empty directory under the system temp directory, removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("morph-bang-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A config whose stores all live in `store`.
+pub fn config_with_store(store: &Path) -> Config {
+    let mut cfg = Config::default();
+    cfg.versions.store_dir = store.to_path_buf();
+    cfg
+}
+
+/// Whether `path` can hold user xattrs; tmpfs before Linux 6.6 cannot.
+pub fn supports_lineage(path: &Path) -> bool {
+    match xattr::set(path, "user.morph-bang.probe", b"") {
+        Ok(()) => xattr::remove(path, "user.morph-bang.probe").is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// An executable in `dir` standing in for a tool, running `script` with
+/// `sh`.
+pub fn fake_tool(dir: &Path, name: &str, script: &str) -> PathBuf {
+    let path = dir.join(name);
+    fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
@@ -0,0 +1,367 @@
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsFd, BorrowedFd};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Recursive inotify watcher over one or more roots.
+///
+/// Emits the full path of every entry moved into a watched directory, plus any
+/// `.!<ext>` names found while catching up on new directories or after the
+/// kernel queue overflowed.
+pub struct Watcher {
+    inotify: Inotify,
+    roots: Vec<PathBuf>,
+    exclude: Vec<PathBuf>,
+    watches: HashMap<WatchDescriptor, PathBuf>,
+    pending_moves: HashMap<u32, PendingMove>,
+    limit_reported: bool,
+}
+
+/// An entry moved into a watched directory.
+pub struct Moved {
+    pub path: PathBuf,
+    /// The path it was renamed from, when both halves of the rename were seen.
+    pub from: Option<PathBuf>,
+}
+
+/// The MOVED_FROM half of a rename, waiting for its MOVED_TO.
+struct PendingMove {
+    path: PathBuf,
+    /// A watched directory, whose watches follow it or go away.
+    watched: bool,
+}
+
+impl Watcher {
+    pub fn new(roots: &[PathBuf], exclude: &[PathBuf]) -> Result<Self> {
+        let inotify =
+            Inotify::init(InitFlags::IN_CLOEXEC).context("failed to initialise inotify")?;
+        let mut watcher = Self {
+            inotify,
+            roots: roots.to_vec(),
+            exclude: exclude.to_vec(),
+            watches: HashMap::new(),
+            pending_moves: HashMap::new(),
+            limit_reported: false,
+        };
+        for root in roots {
+            watcher.add_tree(root, None);
+        }
+        Ok(watcher)
+    }
+
+    pub fn watch_count(&self) -> usize {
+        self.watches.len()
+    }
+
+    /// Applies new roots and exclusions while keeping every watch that is
+    /// still wanted, so no events are lost during a config reload.
+    pub fn reconfigure(&mut self, roots: &[PathBuf], exclude: &[PathBuf]) {
+        let removed_roots: Vec<PathBuf> = self
+            .roots
+            .iter()
+            .filter(|r| !roots.contains(r))
+            .cloned()
+            .collect();
+        let newly_excluded: Vec<PathBuf> = exclude
+            .iter()
+            .filter(|e| !self.exclude.contains(e))
+            .cloned()
+            .collect();
+        let exclusions_lifted = self.exclude.iter().any(|e| !exclude.contains(e));
+        let old_roots = std::mem::replace(&mut self.roots, roots.to_vec());
+        self.exclude = exclude.to_vec();
+
+        for dir in removed_roots.iter().chain(&newly_excluded) {
+            self.drop_tree(dir);
+        }
+        // Dropping a root may have taken watches from a root nested inside it,
+        // and lifting an exclusion exposes trees that were never watched, so
+        // both cases walk every root again. add_watch is idempotent per inode.
+        let rewalk_all = !removed_roots.is_empty() || exclusions_lifted;
+        for root in roots {
+            if rewalk_all || !old_roots.contains(root) {
+                self.add_tree(root, None);
+            }
+        }
+    }
+
+    /// Reads pending inotify events, blocking if there are none, and returns
+    /// the candidate paths they produced.
+    pub fn next_batch(&mut self) -> Result<Vec<Moved>> {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EINTR) => return Ok(Vec::new()),
+            Err(err) => return Err(err).context("failed to read inotify events"),
+        };
+
+        // The kernel queues both halves of a rename together, so a MOVED_FROM
+        // left over from the previous read can only pair with the first event
+        // of this one. Anything still unpaired afterwards was moved out of the tree.
+        let mut stale = std::mem::take(&mut self.pending_moves);
+        let mut found = Vec::new();
+        for event in events {
+            self.handle_event(event, &mut stale, &mut found);
+        }
+        for (_, old) in stale {
+            if old.watched {
+                self.drop_tree(&old.path);
+            }
+        }
+        Ok(found)
+    }
+
+    fn handle_event(
+        &mut self,
+        event: InotifyEvent,
+        stale: &mut HashMap<u32, PendingMove>,
+        found: &mut Vec<Moved>,
+    ) {
+        if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+            eprintln!("Morph Bang: inotify queue overflowed, rescanning watch roots");
+            self.rescan(found);
+            return;
+        }
+        if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+            self.watches.remove(&event.wd);
+            return;
+        }
+
+        let Some(dir) = self.watches.get(&event.wd) else {
+            return;
+        };
+        // A directory whose MOVED_FROM is still unpaired may already be outside
+        // the tree; its watches stay until that is known, but say nothing.
+        let moving = |m: &PendingMove| m.watched && dir.starts_with(&m.path);
+        if self
+            .pending_moves
+            .values()
+            .chain(stale.values())
+            .any(moving)
+        {
+            return;
+        }
+        let Some(name) = event.name.as_deref() else {
+            return;
+        };
+        let path = dir.join(name);
+        let is_dir = event.mask.contains(AddWatchFlags::IN_ISDIR);
+        let ignored = is_hidden(name) || self.is_excluded(&path);
+
+        if event.mask.contains(AddWatchFlags::IN_MOVED_FROM) {
+            // Files are remembered too, so a trigger can be renamed back to
+            // the name it had.
+            let watched = is_dir && !ignored;
+            self.pending_moves
+                .insert(event.cookie, PendingMove { path, watched });
+            return;
+        }
+
+        if event.mask.contains(AddWatchFlags::IN_MOVED_TO) {
+            let old = self
+                .pending_moves
+                .remove(&event.cookie)
+                .or_else(|| stale.remove(&event.cookie));
+            if is_dir {
+                match (old.as_ref().filter(|m| m.watched), ignored) {
+                    (Some(old), true) => self.drop_tree(&old.path),
+                    (Some(old), false) => self.rename_tree(&old.path, &path),
+                    (None, false) => self.add_tree(&path, Some(found)),
+                    (None, true) => {}
+                }
+            }
+            if !ignored {
+                found.push(Moved {
+                    path,
+                    from: old.map(|m| m.path),
+                });
+            }
+            return;
+        }
+
+        if event.mask.contains(AddWatchFlags::IN_CREATE) && is_dir && !ignored {
+            self.add_tree(&path, Some(found));
+        }
+    }
+
+    /// Watches `dir` and everything below it. When `found` is given, trigger
+    /// names that already exist inside the tree are collected, covering files
+    /// that landed before the new watches were in place.
+    fn add_tree(&mut self, dir: &Path, mut found: Option<&mut Vec<Moved>>) {
+        let exclude = self.exclude.clone();
+        let walker = WalkDir::new(dir)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|e| {
+                e.depth() == 0 || !(is_hidden(e.file_name()) || is_excluded(&exclude, e.path()))
+            });
+        for entry in walker.filter_map(|e| e.ok()) {
+            if entry.file_type().is_dir() {
+                self.add_watch(entry.path());
+            }
+            if entry.depth() > 0 && is_trigger_name(entry.file_name()) {
+                if let Some(found) = found.as_deref_mut() {
+                    found.push(Moved {
+                        path: entry.into_path(),
+                        from: None,
+                    });
+                }
+            }
+        }
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        is_excluded(&self.exclude, path)
+    }
+
+    fn add_watch(&mut self, dir: &Path) {
+        match self.inotify.add_watch(dir, watch_mask()) {
+            Ok(wd) => {
+                self.watches.insert(wd, dir.to_path_buf());
+            }
+            Err(Errno::ENOSPC) => {
+                if !self.limit_reported {
+                    eprintln!(
+                        "Morph Bang: inotify watch limit reached at {} watches; raise fs.inotify.max_user_watches",
+                        self.watches.len()
+                    );
+                    self.limit_reported = true;
+                }
+            }
+            Err(Errno::ENOENT) | Err(Errno::ENOTDIR) => {}
+            Err(err) => eprintln!("Morph Bang: failed to watch {}: {err}", dir.display()),
+        }
+    }
+
+    fn rename_tree(&mut self, from: &Path, to: &Path) {
+        for path in self.watches.values_mut() {
+            if let Ok(rel) = path.strip_prefix(from) {
+                *path = to.join(rel);
+            }
+        }
+    }
+
+    fn drop_tree(&mut self, dir: &Path) {
+        let doomed: Vec<WatchDescriptor> = self
+            .watches
+            .iter()
+            .filter(|(_, path)| path.starts_with(dir))
+            .map(|(wd, _)| *wd)
+            .collect();
+        for wd in doomed {
+            self.watches.remove(&wd);
+            let _ = self.inotify.rm_watch(wd);
+        }
+    }
+
+    fn rescan(&mut self, found: &mut Vec<Moved>) {
+        self.pending_moves.clear();
+        let roots = self.roots.clone();
+        for root in &roots {
+            self.add_tree(root, Some(found));
+        }
+    }
+}
+
+impl AsFd for Watcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inotify.as_fd()
+    }
+}
+
+fn watch_mask() -> AddWatchFlags {
+    AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_ONLYDIR
+        | AddWatchFlags::IN_DONT_FOLLOW
+}
+
+fn is_hidden(name: &OsStr) -> bool {
+    name.as_bytes().first() == Some(&b'.')
+}
+
+fn is_excluded(exclude: &[PathBuf], path: &Path) -> bool {
+    exclude.iter().any(|dir| path.starts_with(dir))
+}
+
+pub fn is_trigger_name(name: &OsStr) -> bool {
+    Path::new(name)
+        .extension()
+        .is_some_and(|ext| ext.as_bytes().first() == Some(&b'!'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    fn moves(batch: Vec<Moved>) -> Vec<(PathBuf, Option<PathBuf>)> {
+        batch.into_iter().map(|m| (m.path, m.from)).collect()
+    }
+
+    #[test]
+    fn pairs_both_halves_of_a_rename() {
+        let root = TempDir::new("watch-pairs");
+        fs::write(root.join("photo.png"), "pixels").unwrap();
+        let mut watcher = Watcher::new(&[root.to_path_buf()], &[]).unwrap();
+
+        fs::rename(root.join("photo.png"), root.join("photo.!webp")).unwrap();
+        assert_eq!(
+            moves(watcher.next_batch().unwrap()),
+            [(root.join("photo.!webp"), Some(root.join("photo.png")))]
+        );
+    }
+
+    #[test]
+    fn follows_a_directory_renamed_inside_the_tree() {
+        let root = TempDir::new("watch-rename-dir");
+        fs::create_dir(root.join("old")).unwrap();
+        fs::write(root.join("old/notes.md"), "text").unwrap();
+        let mut watcher = Watcher::new(&[root.to_path_buf()], &[]).unwrap();
+
+        fs::rename(root.join("old"), root.join("new")).unwrap();
+        fs::rename(root.join("new/notes.md"), root.join("new/notes.!pdf")).unwrap();
+        let batch = moves(watcher.next_batch().unwrap());
+        assert_eq!(batch[0], (root.join("new"), Some(root.join("old"))));
+        assert_eq!(
+            batch[1..],
+            [(root.join("new/notes.!pdf"), Some(root.join("new/notes.md")))]
+        );
+        assert_eq!(watcher.watch_count(), 2);
+    }
+
+    #[test]
+    fn drops_a_directory_moved_out_of_the_tree_after_one_more_read() {
+        let root = TempDir::new("watch-stale");
+        let outside = TempDir::new("watch-stale-outside");
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/a.png"), "pixels").unwrap();
+        fs::write(root.join("sub/b.png"), "pixels").unwrap();
+        fs::write(root.join("photo.png"), "pixels").unwrap();
+        let mut watcher = Watcher::new(&[root.to_path_buf()], &[]).unwrap();
+        assert_eq!(watcher.watch_count(), 2);
+
+        // Only the MOVED_FROM half is seen; it waits for a MOVED_TO. Renames
+        // inside the moved directory meanwhile are not reported under the
+        // path it used to have.
+        fs::rename(root.join("sub"), outside.join("sub")).unwrap();
+        fs::rename(outside.join("sub/a.png"), outside.join("sub/a.!webp")).unwrap();
+        assert!(watcher.next_batch().unwrap().is_empty());
+        assert_eq!(watcher.watch_count(), 2);
+
+        // The next read pairs nothing with it, so its watches go.
+        fs::rename(outside.join("sub/b.png"), outside.join("sub/b.!webp")).unwrap();
+        fs::rename(root.join("photo.png"), root.join("photo.!webp")).unwrap();
+        assert_eq!(
+            moves(watcher.next_batch().unwrap()),
+            [(root.join("photo.!webp"), Some(root.join("photo.png")))]
+        );
+        assert_eq!(watcher.watch_count(), 1);
+    }
+}